@@ -122,7 +122,7 @@ class AppDelegate: NSObject, NSApplicationDelegate {
 //! Welcome to the Rust Playground!
 //!
 //! To use external crates, start a line with:
-//! '//~ use crate [= "1.0"]'
+//! '//~ use crate [= "1.0"] [features = a,b]'
 
 // Equivalent to `rand = "*"` in Cargo.toml:
 //
@@ -13,6 +13,8 @@ import Foundation
 struct PlaygroundError {
     let message: String
     let code: Int32
+    /// Fields specific to the kind of error, such as `path` or `exit_code`.
+    let details: [String: AnyObject]
 }
 
 extension PlaygroundError: Error {}
@@ -86,7 +88,13 @@ extension PlaygroundResult {
 
         if error.code != 0 {
             let message = String(cString: error.message, encoding: .utf8)!
-            let error = PlaygroundError(message: message, code: error.code)
+            var details = [String: AnyObject]()
+            if let detailsPtr = playgroundTakeErrorDetails() {
+                defer { playgroundStringFree(detailsPtr) }
+                let string = String(cString: detailsPtr, encoding: .utf8)!
+                details = (try? JSONSerialization.jsonObject(with: string.data(using: .utf8)!)) as? [String: AnyObject] ?? [:]
+            }
+            let error = PlaygroundError(message: message, code: error.code, details: details)
             return .failure(error)
         }
         guard let cString = result else {
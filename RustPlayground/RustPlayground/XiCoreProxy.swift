@@ -19,6 +19,7 @@ class XiCoreProxy {
          updateCallback: @escaping (@convention(c) (Int, Int) -> Void),
          widthMeasure: @escaping (@convention(c) (UnsafePointer<Int8>?) -> XiSize)
         ) {
+        xiSetErrorCallback(coreErrorCallback)
         _inner = xiCoreCreate(rpcCallback, updateCallback, widthMeasure)
     }
 
@@ -156,6 +157,13 @@ class XiCoreProxy {
     }
 }
 
+/// Called over the FFI boundary when a call into the core fails, instead
+/// of the failure crashing the app.
+fileprivate func coreErrorCallback(code: Int32, messagePtr: UnsafePointer<Int8>?) {
+    let message = messagePtr.map { String(cString: $0) } ?? ""
+    print("xi core error \(code): \(message)")
+}
+
 struct StyleSpan {
     let start: Int
     let len: Int
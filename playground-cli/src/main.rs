@@ -0,0 +1,272 @@
+//! A command-line interface to `playground-utils`, so that snippets can be
+//! built and run from scripts, CI and editors.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use playground_utils::{
+    do_compile_task, do_compile_task_with_output, format_code, list_toolchains, Error, Stream, Task,
+};
+
+/// The exit code used when the playground itself fails, as opposed to the
+/// snippet.
+const INTERNAL_ERROR: i32 = 2;
+
+#[derive(Debug)]
+enum CliError {
+    Playground(Error),
+    /// Reading or writing a snippet failed.
+    Io(String, io::Error),
+    Usage(&'static str),
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let json = matches.is_present("json");
+    let exit_code = match run_command(&matches, json) {
+        Ok(code) => code,
+        Err(e) => {
+            if json {
+                let details = match &e {
+                    CliError::Playground(e) => e.details(),
+                    _ => json!({ "code": INTERNAL_ERROR, "message": e.to_string() }),
+                };
+                println!("{}", json!({ "error": details }));
+            } else {
+                eprintln!("error: {}", e);
+            }
+            INTERNAL_ERROR
+        }
+    };
+    process::exit(exit_code);
+}
+
+fn app() -> App<'static, 'static> {
+    let file = Arg::with_name("FILE").help("The snippet to use; reads stdin if omitted or '-'");
+    let toolchain = Arg::with_name("toolchain")
+        .short("t")
+        .long("toolchain")
+        .takes_value(true)
+        .default_value("stable")
+        .help("The rustup toolchain to use");
+    let outdir = Arg::with_name("outdir")
+        .long("outdir")
+        .takes_value(true)
+        .help("The directory to build in; defaults to a directory in the user's cache");
+    let release = Arg::with_name("release").long("release").help("Build in release mode");
+    let backtrace =
+        Arg::with_name("backtrace").long("backtrace").help("Set RUST_BACKTRACE when running");
+
+    let build = |name: &'static str, about: &'static str| {
+        SubCommand::with_name(name).about(about).args(&[
+            file.clone(),
+            toolchain.clone(),
+            outdir.clone(),
+            release.clone(),
+            backtrace.clone(),
+        ])
+    };
+
+    App::new("playground")
+        .about("Builds and runs Rust snippets")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("json").long("json").global(true).help("Print results as json"))
+        .subcommand(build("run", "Builds and runs a snippet"))
+        .subcommand(build("check", "Checks a snippet for errors"))
+        .subcommand(build("test", "Runs a snippet's tests"))
+        .subcommand(SubCommand::with_name("fmt").about("Formats a snippet with rustfmt").args(&[
+            file.clone(),
+            toolchain.clone(),
+            Arg::with_name("write").long("write").help("Overwrite FILE instead of printing"),
+        ]))
+        .subcommand(SubCommand::with_name("toolchains").about("Lists the installed toolchains"))
+        .subcommand(
+            SubCommand::with_name("clean").about("Removes build artifacts").arg(outdir.clone()),
+        )
+}
+
+/// Runs the selected subcommand, returning the process's exit code.
+fn run_command(matches: &ArgMatches, json: bool) -> Result<i32, CliError> {
+    match matches.subcommand() {
+        ("run", Some(args)) => build(args, "run", json),
+        ("check", Some(args)) => build(args, "check", json),
+        ("test", Some(args)) => build(args, "test", json),
+        ("fmt", Some(args)) => fmt(args, json),
+        ("toolchains", Some(_)) => toolchains(json),
+        ("clean", Some(args)) => clean(args, json),
+        _ => Err(CliError::Usage("a subcommand is required")),
+    }
+}
+
+fn build(args: &ArgMatches, task_type: &str, json: bool) -> Result<i32, CliError> {
+    let code = read_snippet(args.value_of("FILE"))?;
+    let outdir = outdir(args);
+    let backtrace = args.is_present("backtrace");
+    let task = make_task(json!({
+        "toolchain": args.value_of("toolchain").unwrap(),
+        "code": code,
+        "task_type": task_type,
+        "backtrace": backtrace,
+        "release": args.is_present("release"),
+    }));
+
+    // in json mode, cargo's output is included in the result instead;
+    // stdout is already part of the compiler result
+    let mut cargo_stderr = String::new();
+    let output = |stream, line: &str| match (stream, json) {
+        (Stream::Stdout, false) => print!("{}", line),
+        (Stream::Stderr, false) => eprint!("{}", line),
+        (Stream::Stdout, true) => (),
+        (Stream::Stderr, true) => cargo_stderr.push_str(line),
+    };
+    let result = do_compile_task_with_output(&outdir, task, output, |_| {})?;
+    let compile = json!({ "result": result, "stderr": cargo_stderr });
+
+    if task_type != "run" || !result.success() {
+        if json {
+            println!("{}", json!({ "compile": compile, "run": Value::Null }));
+        }
+        return Ok(if result.success() { 0 } else { 1 });
+    }
+
+    let executable = result
+        .executable()
+        .ok_or_else(|| Error::bad_exit("The build did not produce an executable."))?;
+    let mut command = Command::new(executable);
+    command.current_dir(&outdir);
+    if backtrace {
+        command.env("RUST_BACKTRACE", "1");
+    }
+
+    if json {
+        let output = command.output().map_err(|e| CliError::Playground(Error::RunFailed(e)))?;
+        let run = json!({
+            "exit_code": output.status.code(),
+            "stdout": String::from_utf8_lossy(&output.stdout),
+            "stderr": String::from_utf8_lossy(&output.stderr),
+        });
+        println!("{}", json!({ "compile": compile, "run": run }));
+        Ok(exit_code(output.status))
+    } else {
+        let status = command.status().map_err(|e| CliError::Playground(Error::RunFailed(e)))?;
+        Ok(exit_code(status))
+    }
+}
+
+fn fmt(args: &ArgMatches, json: bool) -> Result<i32, CliError> {
+    let file = args.value_of("FILE").filter(|f| *f != "-");
+    let code = read_snippet(file)?;
+    let formatted = format_code(args.value_of("toolchain").unwrap(), &code)?;
+
+    if args.is_present("write") {
+        let file = file.ok_or(CliError::Usage("--write requires a FILE"))?;
+        fs::write(file, &formatted).map_err(|e| CliError::Io(file.to_owned(), e))?;
+        if json {
+            println!("{}", json!({ "file": file, "changed": formatted != code }));
+        }
+    } else if json {
+        println!("{}", json!({ "code": formatted }));
+    } else {
+        print!("{}", formatted);
+    }
+    Ok(0)
+}
+
+fn toolchains(json: bool) -> Result<i32, CliError> {
+    let toolchains = list_toolchains()?;
+    if json {
+        println!("{}", serde_json::to_string(&toolchains).unwrap());
+    } else {
+        for toolchain in toolchains.iter() {
+            println!("{}", toolchain.name());
+        }
+    }
+    Ok(0)
+}
+
+fn clean(args: &ArgMatches, json: bool) -> Result<i32, CliError> {
+    let outdir = outdir(args);
+    if !outdir.join("Cargo.toml").exists() {
+        if json {
+            println!("{}", json!({ "cleaned": Value::Null }));
+        }
+        return Ok(0);
+    }
+
+    let task = make_task(json!({
+        "toolchain": "stable",
+        "code": "",
+        "task_type": "clean",
+        "backtrace": false,
+        "release": false,
+    }));
+    // as with builds, cargo's output is printed as it arrives, or included
+    // in the result in json mode
+    let mut cargo_stderr = String::new();
+    let stderr = |line: &str| if json { cargo_stderr.push_str(line) } else { eprint!("{}", line) };
+    let result = do_compile_task(&outdir, task, stderr)?;
+    if json {
+        let compile = json!({ "result": result, "stderr": cargo_stderr });
+        println!("{}", json!({ "cleaned": outdir, "compile": compile }));
+    } else if result.success() {
+        println!("Removed build artifacts in {}", outdir.display());
+    }
+    Ok(if result.success() { 0 } else { 1 })
+}
+
+/// Tasks are only constructed from json, as they are when they come from
+/// the app.
+fn make_task(value: Value) -> Task {
+    serde_json::from_value(value).expect("task json is valid")
+}
+
+fn read_snippet(file: Option<&str>) -> Result<String, CliError> {
+    match file {
+        Some(path) if path != "-" => {
+            fs::read_to_string(path).map_err(|e| CliError::Io(path.to_owned(), e))
+        }
+        _ => {
+            let mut code = String::new();
+            io::stdin()
+                .read_to_string(&mut code)
+                .map_err(|e| CliError::Io("stdin".to_owned(), e))?;
+            Ok(code)
+        }
+    }
+}
+
+fn outdir(args: &ArgMatches) -> PathBuf {
+    match args.value_of("outdir") {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::cache_dir().unwrap_or_else(env::temp_dir).join("playground"),
+    }
+}
+
+/// Programs killed by a signal are reported the way a shell would.
+fn exit_code(status: process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(INTERNAL_ERROR)
+}
+
+impl From<Error> for CliError {
+    fn from(e: Error) -> CliError {
+        CliError::Playground(e)
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Playground(e) => write!(f, "{}", e),
+            CliError::Io(path, e) => write!(f, "Failed to access '{}': {}.", path, e),
+            CliError::Usage(msg) => write!(f, "{}", msg),
+        }
+    }
+}
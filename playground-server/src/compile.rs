@@ -0,0 +1,71 @@
+//! The `compile` and `run` requests, which build on a background thread so
+//! that editing stays responsive.
+
+use std::env;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+
+use playground_utils::{BuildProgress, CompileService, Task};
+use serde_json::{json, Value};
+
+use crate::output::{send_error, send_library_error, send_notification, send_result};
+
+/// `params` is `{"task": <task>, "path": <output dir>}`. If the task has
+/// no `code`, `buffer_text` is used instead; `path` defaults to a directory
+/// in the user's cache.
+///
+/// With `run`, a `run` task's program is run once it is built, in the same
+/// queued job, so that the result is `{"compile", "run"}`.
+///
+/// Returns the thread doing the work, if the request was valid.
+pub fn handle_compile(
+    service: &CompileService,
+    id: Value,
+    params: &Value,
+    buffer_text: String,
+    run: bool,
+) -> Option<JoinHandle<()>> {
+    let mut task_json = params["task"].clone();
+    if task_json.get("code").is_none() {
+        task_json["code"] = Value::String(buffer_text);
+    }
+    let task: Task = match serde_json::from_value(task_json) {
+        Ok(task) => task,
+        Err(e) => {
+            send_error(&id, crate::INVALID_PARAMS, &format!("bad task: {}", e));
+            return None;
+        }
+    };
+    let path = match params["path"].as_str() {
+        Some(path) => PathBuf::from(path),
+        None => dirs::cache_dir().unwrap_or_else(env::temp_dir).join("playground"),
+    };
+
+    let service = service.clone();
+    let handle = thread::spawn(move || {
+        let stderr_id = id.clone();
+        let progress_id = id.clone();
+        let stderr = move |text: &str| {
+            send_notification("compile_stderr", json!({ "id": stderr_id, "text": text }))
+        };
+        let progress = move |event: BuildProgress| {
+            send_notification("compile_progress", json!({ "id": progress_id, "event": event }))
+        };
+        let pending = if run {
+            service.submit_and_run(path, task, stderr, progress)
+        } else {
+            service.submit(path, task, stderr, progress)
+        };
+
+        let result = match pending.wait() {
+            Ok(result) => result,
+            Err(e) => return send_library_error(&id, &e),
+        };
+        if run {
+            send_result(&id, json!({ "compile": result, "run": result.run() }));
+        } else {
+            send_result(&id, json!(result));
+        }
+    });
+    Some(handle)
+}
@@ -0,0 +1,204 @@
+//! A JSON-RPC server over stdio, hosting the editor core and the compiler,
+//! for frontends other than the macOS app.
+//!
+//! Each message is a json object on its own line. Requests have an `id`
+//! and get a response with the same `id` and either a `result` or an
+//! `error`; messages without an `id` are notifications. Any method the core
+//! understands, such as `insert`, `gesture`, `viewport_change` or
+//! `moveDown:`, can be sent as is. In addition, the server handles:
+//!
+//! - `compile` and `run`: `{"task", "path"}`; see `compile::handle_compile`.
+//!   Progress is reported with `compile_stderr` and `compile_progress`.
+//! - `get_lines`: `{"first", "last"}`, returning the lines in that range.
+//! - `get_text`: returning the whole document.
+//! - `toolchains`: returning the installed toolchains.
+//! - `shutdown`.
+//!
+//! Errors from the library have the library's error code, and its details
+//! as `data`; see `Error::details`. Methods that neither the server nor the
+//! core handle get a -32601 error, and malformed params a -32602 error, with
+//! a null `id` for notifications.
+//!
+//! The core's updates are sent as notifications: `content_size`,
+//! `new_styles`, `scroll_to` and `set_pasteboard` as they are in the app,
+//! and `update_lines`, with the contents of any invalidated lines.
+
+mod compile;
+mod output;
+
+use std::io::{self, BufRead};
+use std::thread::JoinHandle;
+
+use libc::{c_char, size_t};
+use playground_utils::{list_toolchains, CompileService};
+use serde_json::{json, Value};
+use xi_modal_input::{Line, OneView, RpcError, XiCore};
+
+use crate::compile::handle_compile;
+use crate::output::{send_error, send_library_error, send_notification, send_result};
+
+/// Error codes for malformed messages, as in JSON-RPC 2.0.
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+/// `OneView::get_line` returns the whole document for this index.
+const WHOLE_DOCUMENT: usize = 6942069;
+
+fn main() {
+    let mut core = new_core();
+    let service = CompileService::new();
+    let mut builds = Vec::new();
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                send_error(&Value::Null, PARSE_ERROR, &format!("invalid json: {}", e));
+                continue;
+            }
+        };
+        builds.retain(|b: &JoinHandle<()>| !b.is_finished());
+        if !handle_message(&mut core, &service, &mut builds, message) {
+            break;
+        }
+    }
+
+    // let running builds report their results before we exit
+    for build in builds {
+        let _ = build.join();
+    }
+}
+
+fn new_core() -> XiCore {
+    let rpc: extern "C" fn(*const c_char) = output::rpc_callback;
+    let invalidate: extern "C" fn(size_t, size_t) = output::invalidate_callback;
+    XiCore::new(rpc, invalidate, OneView::new(output::measure_width))
+}
+
+/// Returns `false` if the server should exit.
+fn handle_message(
+    core: &mut XiCore,
+    service: &CompileService,
+    builds: &mut Vec<JoinHandle<()>>,
+    message: Value,
+) -> bool {
+    let id = message.get("id").cloned();
+    let method = message["method"].as_str().unwrap_or_default();
+    let params = message.get("params").cloned().unwrap_or_else(|| json!({}));
+
+    match (method, id) {
+        ("compile", Some(id)) => {
+            builds.extend(handle_compile(service, id, &params, document_text(core), false))
+        }
+        ("run", Some(id)) => {
+            builds.extend(handle_compile(service, id, &params, document_text(core), true))
+        }
+        ("get_lines", Some(id)) => {
+            let first = params["first"].as_u64().unwrap_or(0) as usize;
+            let last = params["last"].as_u64().map(|l| l as usize).unwrap_or(first + 1);
+            send_result(&id, json!(get_lines(core, first, last)));
+        }
+        ("get_text", Some(id)) => send_result(&id, json!(document_text(core))),
+        ("toolchains", Some(id)) => match list_toolchains() {
+            Ok(toolchains) => send_result(&id, json!(toolchains)),
+            Err(e) => send_library_error(&id, &e),
+        },
+        ("shutdown", id) => {
+            if let Some(id) = id {
+                send_result(&id, Value::Null);
+            }
+            return false;
+        }
+        (method, id) => {
+            let message = json!({ "method": method, "params": params });
+            // as with parse errors, failed notifications are reported with a
+            // null id, so that they are not silently dropped
+            if let Err(e) = core.handle_message(&message.to_string()) {
+                let code = match e {
+                    RpcError::UnknownMethod(_) => METHOD_NOT_FOUND,
+                    RpcError::InvalidMessage(_) | RpcError::InvalidParams { .. } => INVALID_PARAMS,
+                };
+                send_error(&id.unwrap_or(Value::Null), code, &e.to_string());
+                return true;
+            }
+            if let Some(range) = output::take_invalid_lines() {
+                let lines = get_lines(core, range.start, range.end);
+                send_notification("update_lines", json!({ "first": range.start, "lines": lines }));
+            }
+            if let Some(id) = id {
+                send_result(&id, Value::Null);
+            }
+        }
+    }
+    true
+}
+
+/// Returns the lines in `first..last` that exist, with their selection,
+/// caret and style spans.
+fn get_lines(core: &XiCore, first: usize, last: usize) -> Vec<Value> {
+    (first..last)
+        .map_while(|idx| core.state.get_line(idx))
+        .map(|Line { line, caret, selection, styles }| {
+            json!({
+                "text": line,
+                "caret": caret,
+                "selection": [selection.0, selection.1],
+                "styles": styles,
+            })
+        })
+        .collect()
+}
+
+fn document_text(core: &XiCore) -> String {
+    core.state.get_line(WHOLE_DOCUMENT).map(|line| line.line.into_owned()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch() {
+        let output = output::capture_output();
+        let mut core = new_core();
+        let service = CompileService::new();
+        let mut builds = Vec::new();
+        let mut handle = |message: Value| {
+            assert!(handle_message(&mut core, &service, &mut builds, message));
+            let sent = std::mem::take(&mut *output.lock().unwrap());
+            String::from_utf8(sent)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<Vec<Value>>()
+        };
+
+        let sent = handle(json!({ "id": 1, "method": "frobnicate" }));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["jsonrpc"], "2.0");
+        assert_eq!(sent[0]["id"], 1);
+        assert_eq!(sent[0]["error"]["code"], METHOD_NOT_FOUND);
+
+        let sent = handle(json!({ "id": 2, "method": "insert", "params": { "chars": 3 } }));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["error"]["code"], INVALID_PARAMS);
+
+        let sent = handle(json!({ "id": 3, "method": "insert", "params": { "chars": "hi" } }));
+        assert!(sent.iter().all(|message| message["jsonrpc"] == "2.0"));
+        let update = sent.iter().find(|m| m["method"] == "update_lines").expect("no update");
+        assert_eq!(update["params"]["first"], 0);
+        assert!(update["params"]["lines"][0]["text"].as_str().unwrap().starts_with("hi"));
+        // the response comes after the notifications it caused
+        let last = sent.last().unwrap();
+        assert_eq!((&last["id"], &last["result"]), (&json!(3), &Value::Null));
+    }
+}
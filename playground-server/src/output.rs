@@ -0,0 +1,103 @@
+//! Writing messages to stdout, and the callbacks `XiCore` reports through.
+
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use libc::{c_char, size_t};
+use playground_utils::Error;
+use serde_json::{json, Value};
+use xi_modal_input::Size;
+
+lazy_static! {
+    /// Lines invalidated since the last call to `take_invalid_lines`.
+    static ref INVALID_LINES: Mutex<Option<Range<usize>>> = Mutex::new(None);
+    /// Where messages are written; stdout, except in tests.
+    static ref OUTPUT: Mutex<Box<dyn Write + Send>> = Mutex::new(Box::new(io::stdout()));
+}
+
+/// Writes one message per line, marked as JSON-RPC 2.0. Compile tasks
+/// report from other threads, so each message is written while holding the
+/// lock on the output.
+pub fn send(mut message: Value) {
+    message["jsonrpc"] = json!("2.0");
+    let mut out = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = writeln!(out, "{}", message);
+    let _ = out.flush();
+}
+
+pub fn send_notification(method: &str, params: Value) {
+    send(json!({ "method": method, "params": params }));
+}
+
+pub fn send_result(id: &Value, result: Value) {
+    send(json!({ "id": id, "result": result }));
+}
+
+pub fn send_error(id: &Value, code: i32, message: &str) {
+    send(json!({ "id": id, "error": { "code": code, "message": message } }));
+}
+
+/// Reports an error from the library, with its details as the error's
+/// `data`.
+pub fn send_library_error(id: &Value, e: &Error) {
+    let error = json!({ "code": e.error_code(), "message": e.to_string(), "data": e.details() });
+    send(json!({ "id": id, "error": error }));
+}
+
+pub fn take_invalid_lines() -> Option<Range<usize>> {
+    INVALID_LINES.lock().unwrap().take()
+}
+
+/// Receives `content_size`, `new_styles`, `scroll_to` and the like from
+/// the core, already serialized as `{"method", "params"}`.
+pub extern "C" fn rpc_callback(json: *const c_char) {
+    let json = unsafe { CStr::from_ptr(json) };
+    match serde_json::from_slice(json.to_bytes()) {
+        Ok(message) => send(message),
+        Err(e) => eprintln!("invalid notification from the core: {}", e),
+    }
+}
+
+/// Invalidations are collected, and the lines sent once the message that
+/// caused them has been handled; the core can't be read from in here.
+pub extern "C" fn invalidate_callback(start: size_t, end: size_t) {
+    let mut invalid = INVALID_LINES.lock().unwrap();
+    *invalid = match invalid.take() {
+        Some(prev) => Some(prev.start.min(start)..prev.end.max(end)),
+        None => Some(start..end),
+    };
+}
+
+/// There is no font to measure with, so text is laid out in character
+/// cells: each character is one unit wide, and each line one unit high.
+pub extern "C" fn measure_width(text: *const c_char) -> Size {
+    let text = unsafe { CStr::from_ptr(text) };
+    let width = text.to_string_lossy().chars().count();
+    Size { width, height: 1 }
+}
+
+/// Collects all further messages in the returned buffer, instead of
+/// writing them to stdout.
+#[cfg(test)]
+pub fn capture_output() -> std::sync::Arc<Mutex<Vec<u8>>> {
+    use std::sync::Arc;
+
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    *OUTPUT.lock().unwrap() = Box::new(Captured(buffer.clone()));
+    buffer
+}
@@ -3,6 +3,10 @@
 
 typedef const char* json;
 typedef void (*stderr_callback)(const char*);
+typedef void (*progress_callback)(json);
+
+typedef struct _TaskHandle TaskHandle;
+typedef struct _BisectToken BisectToken;
 
 typedef struct _ExternError {
     int32_t code;
@@ -10,8 +14,38 @@ typedef struct _ExternError {
 } ExternError;
 
 extern json playgroundGetToolchains(ExternError* error);
+// Tasks are queued per output directory and run on a background thread.
+// Their stderr and progress callbacks are called on that thread, not on the
+// calling thread, while the call blocks until the task is done.
 extern json playgroundExecuteTask(const char* path, json, stderr_callback, ExternError* error);
+extern json playgroundExecuteTaskWithProgress(const char* path, json, stderr_callback, progress_callback, ExternError* error);
+extern TaskHandle* playgroundStartTask(const char* path, json task, ExternError* error);
+extern json playgroundPollTask(TaskHandle* handle, ExternError* error);
+extern void playgroundCancelTask(TaskHandle* handle);
+extern void playgroundTaskFree(TaskHandle* handle);
+extern json playgroundRunMatrix(const char* path, json task, json toolchains, ExternError* error);
+extern BisectToken* playgroundBisectTokenNew(void);
+extern json playgroundBisectNightlies(const char* path, json task, json predicate, progress_callback, const BisectToken* cancel, ExternError* error);
+extern void playgroundCancelBisect(const BisectToken* token);
+extern void playgroundBisectTokenFree(BisectToken* token);
+extern json playgroundListOutputDirs(const char* root, ExternError* error);
+extern json playgroundCollectGarbage(const char* root, json policy, ExternError* error);
+extern json playgroundAnalyzeBinarySize(const char* path, ExternError* error);
+extern json playgroundCompareBinarySizes(const char* before, const char* after, ExternError* error);
+extern json playgroundExplainError(const char* toolchain, const char* code, ExternError* error);
+extern json playgroundFormatCode(const char* toolchain, const char* code, ExternError* error);
+extern json playgroundListSuggestions(const char* code, const char* diagnostics, ExternError* error);
+extern json playgroundApplySuggestions(const char* code, const char* diagnostics, json selected, ExternError* error);
+extern json playgroundParsePanic(const char* output, const char* path, ExternError* error);
 
+extern json playgroundCompleteCrateName(const char* prefix, const char* usage_file, uint32_t limit, ExternError* error);
+extern json playgroundCrateVersions(const char* name, ExternError* error);
+extern void playgroundRecordCrateUsage(const char* usage_file, const char* code, ExternError* error);
+extern json playgroundListTemplates(const char* user_dir, ExternError* error);
+extern json playgroundInstantiateTemplate(const char* name, const char* user_dir, json values, ExternError* error);
+extern void playgroundSaveTemplate(const char* user_dir, const char* name, const char* description, const char* code, ExternError* error);
+extern json playgroundParseAnsi(const char* text, ExternError* error);
+extern json playgroundTakeErrorDetails(void);
 extern void playgroundStringFree(json);
 
 #endif
@@ -1,46 +1,539 @@
 use libc::c_char;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString, OsStr};
 use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe, UnwindSafe};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use ffi_support::{call_with_result, ExternError};
-use playground_utils::{do_compile_task, list_toolchains, Task};
+use ffi_support::{ExternError, IntoFfi};
+use lazy_static::lazy_static;
+use playground_utils::{
+    analyze_binary_size, apply_suggestions, bisect_nightlies, collect_garbage,
+    compare_binary_sizes, complete_crate_names, crate_versions, explain_error, format_code,
+    instantiate_template, list_output_dirs, list_suggestions, list_templates, list_toolchains,
+    parse_ansi, record_crate_usage, run_matrix, save_template, take_last_error_details,
+    BisectPredicate, CompileService, Error, GcPolicy, PanicReport, Task, TaskHandle,
+};
+use serde::de::DeserializeOwned;
+
+lazy_static! {
+    /// All tasks go through one service, so concurrent requests for the
+    /// same directory are queued instead of racing.
+    static ref COMPILE_SERVICE: CompileService = CompileService::new();
+}
 
 #[no_mangle]
 pub extern "C" fn playgroundGetToolchains(err: &mut ExternError) -> *const c_char {
     call_with_result(err, || list_toolchains().map(|r| serde_json::to_string(&r).unwrap()))
 }
 
+/// Runs a task in `path`, blocking until it has finished. The task is
+/// queued behind any others in `path`, and `std_err_callback` is called on
+/// the thread that runs the queue, not on the calling thread.
 #[no_mangle]
 pub extern "C" fn playgroundExecuteTask(
     path: *const c_char,
     cmd_json: *const c_char,
     std_err_callback: extern "C" fn(*const c_char),
     err: &mut ExternError,
+) -> *const c_char {
+    playgroundExecuteTaskWithProgress(path, cmd_json, std_err_callback, ignore_progress, err)
+}
+
+/// Like `playgroundExecuteTask`, but also reports build progress events
+/// as json to `progress_callback`.
+#[no_mangle]
+pub extern "C" fn playgroundExecuteTaskWithProgress(
+    path: *const c_char,
+    cmd_json: *const c_char,
+    std_err_callback: extern "C" fn(*const c_char),
+    progress_callback: extern "C" fn(*const c_char),
+    err: &mut ExternError,
 ) -> *const c_char {
     call_with_result(err, || {
-        eprintln!("playground execute task");
-        let path = unsafe { CStr::from_ptr(path) };
-        let json = unsafe { CStr::from_ptr(cmd_json) };
-        let path = Path::new(OsStr::from_bytes(path.to_bytes()));
-        let json = json.to_str().expect("json must be valid utf8");
-        let task: Task = serde_json::from_str(json).expect("malformed task json");
-        do_compile_task(path, task, |stderr| {
-            let cstring = CString::new(stderr)
-                .unwrap_or_else(|_| CString::new("null byte in stderr").unwrap());
-            std_err_callback(cstring.as_ptr());
-        })
+        let path = path_arg(path, "path")?;
+        let task: Task = json_arg(cmd_json, "task json")?;
+        COMPILE_SERVICE
+            .submit(
+                path,
+                task,
+                move |stderr| {
+                    let cstring = CString::new(stderr)
+                        .unwrap_or_else(|_| CString::new("null byte in stderr").unwrap());
+                    std_err_callback(cstring.as_ptr());
+                },
+                move |progress| {
+                    let json = serde_json::to_string(&progress).unwrap();
+                    let cstring = CString::new(json).unwrap();
+                    progress_callback(cstring.as_ptr());
+                },
+            )
+            .wait()
+            .map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+extern "C" fn ignore_progress(_: *const c_char) {}
+
+/// Starts a task in the background, returning a handle for polling and
+/// cancelling it, or null if the arguments are invalid. The task is queued
+/// behind any others in the same directory. The handle must be freed with
+/// `playgroundTaskFree`.
+#[no_mangle]
+pub extern "C" fn playgroundStartTask(
+    path: *const c_char,
+    cmd_json: *const c_char,
+    err: &mut ExternError,
+) -> *mut TaskHandle {
+    let mut handle = std::ptr::null_mut();
+    call_with_result(
+        err,
+        AssertUnwindSafe(|| {
+            let path = path_arg(path, "path")?;
+            let task: Task = json_arg(cmd_json, "task json")?;
+            handle = Box::into_raw(Box::new(COMPILE_SERVICE.start(path, task)));
+            Ok::<_, Error>(())
+        }),
+    );
+    handle
+}
+
+/// Returns a json array of the task's events since the last poll, without
+/// blocking. The last event is either `finished` or `failed`.
+#[no_mangle]
+pub extern "C" fn playgroundPollTask(ptr: *mut TaskHandle, err: &mut ExternError) -> *const c_char {
+    call_with_result(
+        err,
+        AssertUnwindSafe(|| {
+            let handle = unsafe { ptr.as_mut() }
+                .ok_or_else(|| Error::InvalidInput("task handle is null".into()))?;
+            let events = std::iter::from_fn(|| handle.try_next()).collect::<Vec<_>>();
+            Ok::<_, Error>(serde_json::to_string(&events).unwrap())
+        }),
+    )
+}
+
+/// Cancels a task; it will finish with a `failed` event.
+#[no_mangle]
+pub extern "C" fn playgroundCancelTask(ptr: *mut TaskHandle) {
+    guard("playgroundCancelTask", || {
+        if let Some(handle) = unsafe { ptr.as_ref() } {
+            handle.cancel();
+        }
+    })
+}
+
+/// Frees a task handle. A task that is still running is not cancelled.
+#[no_mangle]
+pub extern "C" fn playgroundTaskFree(ptr: *mut TaskHandle) {
+    if ptr.is_null() {
+        return;
+    }
+
+    guard("playgroundTaskFree", || unsafe { drop(Box::from_raw(ptr)) })
+}
+
+/// Runs a task on each toolchain in `toolchains_json`, a json array of
+/// toolchain names, using a subdirectory of `path` for each. Each build is
+/// queued like any other task in its directory.
+#[no_mangle]
+pub extern "C" fn playgroundRunMatrix(
+    path: *const c_char,
+    cmd_json: *const c_char,
+    toolchains_json: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let path = path_arg(path, "path")?;
+        let task: Task = json_arg(cmd_json, "task json")?;
+        let toolchains: Vec<String> = json_arg(toolchains_json, "toolchains json")?;
+        run_matrix(&COMPILE_SERVICE, path, &task, &toolchains)
+            .map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Returns a token that cancels the bisection it is passed to; see
+/// `playgroundCancelBisect`. It must be freed with `playgroundBisectTokenFree`
+/// once that bisection has returned.
+#[no_mangle]
+pub extern "C" fn playgroundBisectTokenNew() -> *mut AtomicBool {
+    Box::into_raw(Box::new(AtomicBool::new(false)))
+}
+
+/// Bisects the installed dated nightlies for the first in which the
+/// predicate in `predicate_json` changes, reporting progress events as json.
+/// `cancel` is a token from `playgroundBisectTokenNew`, or null if the
+/// bisection will not be cancelled.
+#[no_mangle]
+pub extern "C" fn playgroundBisectNightlies(
+    path: *const c_char,
+    cmd_json: *const c_char,
+    predicate_json: *const c_char,
+    progress_callback: extern "C" fn(*const c_char),
+    cancel: *const AtomicBool,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let path = path_arg(path, "path")?;
+        let task: Task = json_arg(cmd_json, "task json")?;
+        let predicate: BisectPredicate = json_arg(predicate_json, "predicate json")?;
+        let never = AtomicBool::new(false);
+        let cancel = unsafe { cancel.as_ref() }.unwrap_or(&never);
+        bisect_nightlies(
+            &COMPILE_SERVICE,
+            path,
+            &task,
+            &predicate,
+            |progress| {
+                let json = serde_json::to_string(&progress).unwrap();
+                let cstring = CString::new(json).unwrap();
+                progress_callback(cstring.as_ptr());
+            },
+            cancel,
+        )
         .map(|r| serde_json::to_string(&r).unwrap())
     })
 }
 
+/// Cancels the bisection that was given `token`, before it tests another
+/// toolchain. This can be called from any thread.
+#[no_mangle]
+pub extern "C" fn playgroundCancelBisect(token: *const AtomicBool) {
+    guard("playgroundCancelBisect", || {
+        if let Some(token) = unsafe { token.as_ref() } {
+            token.store(true, Ordering::SeqCst);
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn playgroundBisectTokenFree(token: *mut AtomicBool) {
+    if token.is_null() {
+        return;
+    }
+
+    guard("playgroundBisectTokenFree", || unsafe { drop(Box::from_raw(token)) })
+}
+
+/// Lists the output directories under `root`, with their size and last use.
+#[no_mangle]
+pub extern "C" fn playgroundListOutputDirs(
+    root: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let root = path_arg(root, "root")?;
+        list_output_dirs(root).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Removes the output directories under `root` selected by `policy_json`.
+#[no_mangle]
+pub extern "C" fn playgroundCollectGarbage(
+    root: *const c_char,
+    policy_json: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let root = path_arg(root, "root")?;
+        let policy: GcPolicy = json_arg(policy_json, "policy json")?;
+        collect_garbage(root, &policy).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn playgroundAnalyzeBinarySize(
+    path: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let path = path_arg(path, "path")?;
+        analyze_binary_size(path).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn playgroundCompareBinarySizes(
+    before: *const c_char,
+    after: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let before = path_arg(before, "before")?;
+        let after = path_arg(after, "after")?;
+        compare_binary_sizes(before, after).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Returns the markdown text of `rustc --explain` for an error code, as json.
+#[no_mangle]
+pub extern "C" fn playgroundExplainError(
+    toolchain: *const c_char,
+    code: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let toolchain = lossy_arg(toolchain, "toolchain")?;
+        let code = lossy_arg(code, "code")?;
+        explain_error(&toolchain, &code).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Formats a snippet with the toolchain's rustfmt, returning the code as json.
+#[no_mangle]
+pub extern "C" fn playgroundFormatCode(
+    toolchain: *const c_char,
+    code: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let toolchain = lossy_arg(toolchain, "toolchain")?;
+        let code = lossy_arg(code, "code")?;
+        format_code(&toolchain, &code).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Returns the machine-applicable suggestions for a snippet, given the json
+/// diagnostics from a task with `json_diagnostics` set.
+#[no_mangle]
+pub extern "C" fn playgroundListSuggestions(
+    code: *const c_char,
+    diagnostics: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let code = lossy_arg(code, "code")?;
+        let diagnostics = lossy_arg(diagnostics, "diagnostics")?;
+        let suggestions = list_suggestions(&code, &diagnostics);
+        Ok::<_, Error>(serde_json::to_string(&suggestions).unwrap())
+    })
+}
+
+/// Applies suggestions to a snippet. `selected_json` is a json array of
+/// suggestion indices; if it is null, all suggestions are applied.
+#[no_mangle]
+pub extern "C" fn playgroundApplySuggestions(
+    code: *const c_char,
+    diagnostics: *const c_char,
+    selected_json: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let code = lossy_arg(code, "code")?;
+        let diagnostics = lossy_arg(diagnostics, "diagnostics")?;
+        let selected: Option<Vec<usize>> = if selected_json.is_null() {
+            None
+        } else {
+            Some(json_arg(selected_json, "selection json")?)
+        };
+        let result = apply_suggestions(&code, &diagnostics, selected.as_deref());
+        Ok::<_, Error>(serde_json::to_string(&result).unwrap())
+    })
+}
+
+/// Parses the first panic in a program's output, returning `null` if
+/// there is none. `path` is the directory the program was built in, or
+/// null; backtrace frames with absolute paths are only matched to the
+/// snippet if they are in it.
+#[no_mangle]
+pub extern "C" fn playgroundParsePanic(
+    output: *const c_char,
+    path: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let output = lossy_arg(output, "output")?;
+        let path = if path.is_null() { None } else { Some(path_arg(path, "path")?) };
+        Ok::<_, Error>(serde_json::to_string(&PanicReport::parse(&output, path)).unwrap())
+    })
+}
+
+/// Converts the ANSI escape sequences in `text` into styled spans, returning
+/// the plain text and the spans as json.
+#[no_mangle]
+pub extern "C" fn playgroundParseAnsi(text: *const c_char, err: &mut ExternError) -> *const c_char {
+    call_with_result(err, || {
+        let text = lossy_arg(text, "text")?;
+        Ok::<_, Error>(serde_json::to_string(&parse_ansi(&text)).unwrap())
+    })
+}
+
+/// Returns up to `limit` crate names from the local registry index cache
+/// that start with `prefix`, as json, ranked by the usage recorded in
+/// `usage_file`, which may be null.
+#[no_mangle]
+pub extern "C" fn playgroundCompleteCrateName(
+    prefix: *const c_char,
+    usage_file: *const c_char,
+    limit: u32,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let prefix = lossy_arg(prefix, "prefix")?;
+        let usage_file =
+            if usage_file.is_null() { None } else { Some(path_arg(usage_file, "usage file")?) };
+        let completions = complete_crate_names(&prefix, usage_file, limit as usize);
+        Ok::<_, Error>(serde_json::to_string(&completions).unwrap())
+    })
+}
+
+/// Returns the versions of a crate in the local registry index cache, with
+/// their features, as json.
+#[no_mangle]
+pub extern "C" fn playgroundCrateVersions(
+    name: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let name = str_arg(name, "name")?;
+        crate_versions(name).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Records the crates used by a snippet's `//~ use` lines in `usage_file`,
+/// for ranking completions.
+#[no_mangle]
+pub extern "C" fn playgroundRecordCrateUsage(
+    usage_file: *const c_char,
+    code: *const c_char,
+    err: &mut ExternError,
+) {
+    call_with_result(err, || {
+        let usage_file = path_arg(usage_file, "usage file")?;
+        let code = lossy_arg(code, "code")?;
+        record_crate_usage(usage_file, &code)
+    })
+}
+
+/// Returns the built-in templates and those in `user_dir`, which may be
+/// null, as json.
+#[no_mangle]
+pub extern "C" fn playgroundListTemplates(
+    user_dir: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let user_dir =
+            if user_dir.is_null() { None } else { Some(path_arg(user_dir, "user dir")?) };
+        Ok::<_, Error>(serde_json::to_string(&list_templates(user_dir)).unwrap())
+    })
+}
+
+/// Creates a snippet from a template, returning its code as a json string.
+/// `values` is a json object of placeholder names to values.
+#[no_mangle]
+pub extern "C" fn playgroundInstantiateTemplate(
+    name: *const c_char,
+    user_dir: *const c_char,
+    values: *const c_char,
+    err: &mut ExternError,
+) -> *const c_char {
+    call_with_result(err, || {
+        let name = str_arg(name, "name")?;
+        let user_dir =
+            if user_dir.is_null() { None } else { Some(path_arg(user_dir, "user dir")?) };
+        let values: HashMap<String, String> = json_arg(values, "values")?;
+        instantiate_template(name, user_dir, &values).map(|r| serde_json::to_string(&r).unwrap())
+    })
+}
+
+/// Saves a snippet as a template in `user_dir`.
+#[no_mangle]
+pub extern "C" fn playgroundSaveTemplate(
+    user_dir: *const c_char,
+    name: *const c_char,
+    description: *const c_char,
+    code: *const c_char,
+    err: &mut ExternError,
+) {
+    call_with_result(err, || {
+        let user_dir = path_arg(user_dir, "user dir")?;
+        let name = str_arg(name, "name")?;
+        let description = lossy_arg(description, "description")?;
+        let code = lossy_arg(code, "code")?;
+        save_template(user_dir, name, &description, &code)
+    })
+}
+
+/// After a call reports an error, returns the error's details as json:
+/// its `code` and `message`, and any fields specific to the error. Returns
+/// null if there is no error on this thread to report.
+#[no_mangle]
+pub extern "C" fn playgroundTakeErrorDetails() -> *mut c_char {
+    match take_last_error_details() {
+        Some(json) => CString::new(json).unwrap().into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn playgroundStringFree(ptr: *mut c_char) {
     if ptr.is_null() {
         return;
     }
 
-    unsafe {
-        CString::from_raw(ptr);
+    guard("playgroundStringFree", || unsafe { drop(CString::from_raw(ptr)) })
+}
+
+/// Wraps `ffi_support::call_with_result`, first clearing the details of any
+/// earlier error on this thread, so that `playgroundTakeErrorDetails` never
+/// reports them for an error from this call, such as a panic.
+fn call_with_result<R, E, F>(err: &mut ExternError, callback: F) -> R::Value
+where
+    F: UnwindSafe + FnOnce() -> Result<R, E>,
+    E: Into<ExternError>,
+    R: IntoFfi,
+{
+    take_last_error_details();
+    ffi_support::call_with_result(err, callback)
+}
+
+/// Runs the body of an exported function that has no error to report
+/// through, so that a panic does not unwind into the caller. The panic is
+/// printed instead.
+fn guard<F: FnOnce()>(name: &str, f: F) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+        let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg
+        } else {
+            "unknown panic"
+        };
+        eprintln!("panic in {}: {}", name, message);
+    }
+}
+
+/// Reads a string argument, failing if it is null or not utf8.
+fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error::InvalidInput(format!("{} is null", name)));
+    }
+    let cstr = unsafe { CStr::from_ptr(ptr) };
+    cstr.to_str().map_err(|e| Error::InvalidInput(format!("{} is not utf8: {}", name, e)))
+}
+
+/// Like `str_arg`, but invalid utf8 is replaced rather than rejected.
+fn lossy_arg<'a>(ptr: *const c_char, name: &str) -> Result<Cow<'a, str>, Error> {
+    if ptr.is_null() {
+        return Err(Error::InvalidInput(format!("{} is null", name)));
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }.to_string_lossy())
+}
+
+/// Paths are passed as bytes, and need not be utf8.
+fn path_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a Path, Error> {
+    if ptr.is_null() {
+        return Err(Error::InvalidInput(format!("{} is null", name)));
     }
+    let cstr = unsafe { CStr::from_ptr(ptr) };
+    Ok(Path::new(OsStr::from_bytes(cstr.to_bytes())))
+}
+
+fn json_arg<T: DeserializeOwned>(ptr: *const c_char, name: &str) -> Result<T, Error> {
+    let json = str_arg(ptr, name)?;
+    serde_json::from_str(json)
+        .map_err(|e| Error::InvalidInput(format!("malformed {}: {}", name, e)))
 }
@@ -0,0 +1,241 @@
+//! Converting ANSI escape sequences in output into styled text.
+
+/// An ARGB color, as in the editor's styles. Zero means the default color.
+pub type RgbaColor = u32;
+
+/// The style of a span of output, with the same fields as the editor's
+/// styles, so that frontends can render both the same way.
+#[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq, Serialize)]
+pub struct Style {
+    foreground: RgbaColor,
+    background: RgbaColor,
+    italic: bool,
+    bold: bool,
+    underline: bool,
+}
+
+/// A run of text with a non-default style. Offsets are in utf8 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StyledSpan {
+    start: usize,
+    len: usize,
+    style: Style,
+}
+
+/// Output with escape sequences removed, and the styles they described.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StyledText {
+    text: String,
+    spans: Vec<StyledSpan>,
+}
+
+impl StyledText {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn spans(&self) -> &[StyledSpan] {
+        &self.spans
+    }
+
+    /// Adds a span, extending the previous one if it is adjacent and has
+    /// the same style.
+    fn push_span(&mut self, start: usize, len: usize, style: Style) {
+        match self.spans.last_mut() {
+            Some(last) if last.style == style && last.start + last.len == start => last.len += len,
+            _ => self.spans.push(StyledSpan { start, len, style }),
+        }
+    }
+}
+
+/// The 16 basic colors, as xterm draws them.
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+
+/// Parses the SGR ("select graphic rendition") sequences in `input` into
+/// styled spans. Other escape sequences, such as cursor movement, are
+/// removed.
+pub fn parse_ansi(input: &str) -> StyledText {
+    let mut result = StyledText::default();
+    let mut style = Style::default();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            let start = result.text.len();
+            result.text.push(c);
+            if style != Style::default() {
+                result.push_span(start, c.len_utf8(), style);
+            }
+            continue;
+        }
+
+        match chars.next() {
+            // CSI: parameters, then a final byte in '@'..='~'
+            Some('[') => {
+                let mut params = String::new();
+                let mut last = None;
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        last = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                if last == Some('m') {
+                    style.apply_sgr(&params);
+                }
+            }
+            // OSC, e.g. hyperlinks: ends with BEL or ESC '\'
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == BEL || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // other escapes are a single character
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Removes escape sequences, for when output is parsed rather than shown.
+pub(crate) fn strip_ansi(input: &str) -> String {
+    if input.contains(ESC) {
+        parse_ansi(input).text
+    } else {
+        input.to_owned()
+    }
+}
+
+impl Style {
+    fn apply_sgr(&mut self, params: &str) {
+        // a missing parameter means 0, so "\x1b[m" is a reset
+        let mut codes = params.split(';').map(|p| p.parse::<u32>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => self.bold = false,
+                23 => self.italic = false,
+                24 => self.underline = false,
+                30..=37 => self.foreground = basic_color(code - 30),
+                90..=97 => self.foreground = basic_color(code - 90 + 8),
+                39 => self.foreground = 0,
+                40..=47 => self.background = basic_color(code - 40),
+                100..=107 => self.background = basic_color(code - 100 + 8),
+                49 => self.background = 0,
+                38 => self.foreground = extended_color(&mut codes).unwrap_or(self.foreground),
+                48 => self.background = extended_color(&mut codes).unwrap_or(self.background),
+                // dim, blink, inverse and the like are not representable
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Reads the rest of a 256-color (`5;n`) or truecolor (`2;r;g;b`) code.
+fn extended_color<I: Iterator<Item = u32>>(codes: &mut I) -> Option<RgbaColor> {
+    match codes.next()? {
+        5 => Some(indexed_color(codes.next()?)),
+        2 => {
+            let (r, g, b) = (codes.next()?, codes.next()?, codes.next()?);
+            Some(rgba(r.min(255) as u8, g.min(255) as u8, b.min(255) as u8))
+        }
+        _ => None,
+    }
+}
+
+fn basic_color(idx: u32) -> RgbaColor {
+    let (r, g, b) = PALETTE[idx as usize];
+    rgba(r, g, b)
+}
+
+/// The xterm 256-color palette: the basic colors, a 6x6x6 cube, and a
+/// ramp of grays.
+fn indexed_color(idx: u32) -> RgbaColor {
+    match idx {
+        0..=15 => basic_color(idx),
+        16..=231 => {
+            let level = |n: u32| if n == 0 { 0 } else { (55 + n * 40) as u8 };
+            let idx = idx - 16;
+            rgba(level(idx / 36), level(idx / 6 % 6), level(idx % 6))
+        }
+        232..=255 => {
+            let gray = (8 + (idx - 232) * 10) as u8;
+            rgba(gray, gray, gray)
+        }
+        _ => 0,
+    }
+}
+
+fn rgba(r: u8, g: u8, b: u8) -> RgbaColor {
+    (0xff << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cargo_error() {
+        let input = "\u{1b}[0m\u{1b}[1m\u{1b}[38;5;9merror[E0308]\u{1b}[0m\u{1b}[0m\u{1b}[1m: \
+                     mismatched types\u{1b}[0m\n";
+        let styled = parse_ansi(input);
+        assert_eq!(styled.text(), "error[E0308]: mismatched types\n");
+        let red = Style { foreground: rgba(255, 0, 0), bold: true, ..Style::default() };
+        let bold = Style { bold: true, ..Style::default() };
+        assert_eq!(
+            styled.spans(),
+            &[
+                StyledSpan { start: 0, len: 12, style: red },
+                StyledSpan { start: 12, len: 18, style: bold },
+            ]
+        );
+    }
+
+    #[test]
+    fn colors_and_resets() {
+        let styled = parse_ansi("\u{1b}[32;4mok\u{1b}[24m!\u{1b}[39m \u{1b}[48;2;1;2;3mé\u{1b}[m");
+        assert_eq!(styled.text(), "ok! é");
+        let green = Style { foreground: rgba(0, 205, 0), ..Style::default() };
+        assert_eq!(styled.spans()[0].style, Style { underline: true, ..green });
+        assert_eq!(styled.spans()[1], StyledSpan { start: 2, len: 1, style: green });
+        assert_eq!(styled.spans()[2].start, 4);
+        assert_eq!(styled.spans()[2].len, 2);
+        assert_eq!(styled.spans()[2].style.background, rgba(1, 2, 3));
+    }
+
+    #[test]
+    fn other_sequences_removed() {
+        let input = "\u{1b}[2K\u{1b}]8;;https://rust-lang.org\u{7}link\u{1b}]8;;\u{1b}\\ done";
+        let styled = parse_ansi(input);
+        assert_eq!(styled.text(), "link done");
+        assert!(styled.spans().is_empty());
+        assert_eq!(indexed_color(196), rgba(255, 0, 0));
+        assert_eq!(indexed_color(244), rgba(128, 128, 128));
+    }
+}
@@ -0,0 +1,302 @@
+//! Finding the nightly in which a snippet's behaviour changed.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::compile::Task;
+use crate::error::Error;
+use crate::matrix::{run_entry, MatrixEntry};
+use crate::rustup;
+use crate::service::CompileService;
+use crate::toolchain;
+
+/// The behaviour we are looking for a change in.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BisectPredicate {
+    /// The build succeeds.
+    Compiles,
+    /// The build succeeds and, for `run` tasks, the program exits with 0,
+    /// rather than failing or being killed by a signal.
+    RunsSuccessfully,
+    /// The program's output, or cargo's for `test` tasks, contains `text`.
+    OutputContains { text: String },
+}
+
+/// A structured event describing the progress of a bisection.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BisectProgress {
+    /// A toolchain is being tested. `remaining` is the number of toolchains
+    /// that will be tested after this one, at most.
+    Testing { toolchain: String, remaining: usize },
+    /// A toolchain was tested.
+    Tested { toolchain: String, holds: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BisectStep {
+    toolchain: String,
+    /// `true` if the predicate held on this toolchain.
+    holds: bool,
+}
+
+/// The result of a bisection.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BisectResult {
+    /// Whether the predicate held on the oldest and newest nightlies.
+    oldest_holds: bool,
+    newest_holds: bool,
+    /// The last nightly that behaves like the oldest, and the first that
+    /// behaves like the newest. `None` if the two behave the same.
+    last_before: Option<String>,
+    first_after: Option<String>,
+    /// Every toolchain tested, in the order they were tested.
+    steps: Vec<BisectStep>,
+}
+
+impl BisectPredicate {
+    fn holds(&self, entry: &MatrixEntry) -> bool {
+        match self {
+            BisectPredicate::Compiles => entry.compiled(),
+            // if the program was not run, building it is all we can check
+            BisectPredicate::RunsSuccessfully => {
+                entry.compiled() && entry.run_succeeded().unwrap_or(true)
+            }
+            BisectPredicate::OutputContains { text } => {
+                entry.stdout().map(|s| s.contains(text.as_str())).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Binary-searches the installed dated nightlies for this host, e.g.
+/// `nightly-2023-01-01-aarch64-apple-darwin`, for the first in which
+/// `predicate` gives a different answer than on the oldest.
+///
+/// This assumes the behaviour changed once; if it changed back and forth,
+/// one of the changes is found. `cancel` is checked before each toolchain
+/// is tested. Each build is queued with `service`.
+pub fn bisect_nightlies<P, F>(
+    service: &CompileService,
+    outdir: P,
+    task: &Task,
+    predicate: &BisectPredicate,
+    progress: F,
+    cancel: &AtomicBool,
+) -> Result<BisectResult, Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(BisectProgress),
+{
+    let outdir = outdir.as_ref();
+    let toolchains = native_nightlies(rustup::list_toolchains()?, &toolchain::native_host());
+
+    let test = |toolchain: &str| {
+        let entry =
+            run_entry(service, outdir, task.with_toolchain(toolchain), toolchain.to_owned());
+        match entry.error() {
+            Some(e) => Err(Error::bad_exit(format!("Toolchain '{}' failed: {}", toolchain, e))),
+            None => Ok(predicate.holds(&entry)),
+        }
+    };
+    search(&toolchains, test, progress, cancel)
+}
+
+/// The search itself, over toolchains ordered from oldest to newest.
+fn search<T, F>(
+    toolchains: &[String],
+    mut test: T,
+    mut progress: F,
+    cancel: &AtomicBool,
+) -> Result<BisectResult, Error>
+where
+    T: FnMut(&str) -> Result<bool, Error>,
+    F: FnMut(BisectProgress),
+{
+    if toolchains.len() < 2 {
+        return Err(Error::NotEnoughNightlies(toolchains.len()));
+    }
+
+    let mut steps = Vec::new();
+    let mut test_at = |idx: usize, remaining: usize| -> Result<bool, Error> {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+        let toolchain = toolchains[idx].clone();
+        progress(BisectProgress::Testing { toolchain: toolchain.clone(), remaining });
+        let holds = test(&toolchain)?;
+        progress(BisectProgress::Tested { toolchain: toolchain.clone(), holds });
+        steps.push(BisectStep { toolchain, holds });
+        Ok(holds)
+    };
+
+    // the oldest toolchain is at `lo` and the newest at `hi`; we narrow the
+    // range while keeping the oldest behaviour at `lo` and the newest at `hi`.
+    let (mut lo, mut hi) = (0, toolchains.len() - 1);
+    let oldest_holds = test_at(lo, steps_needed(lo, hi) + 1)?;
+    let newest_holds = test_at(hi, steps_needed(lo, hi))?;
+    if oldest_holds == newest_holds {
+        return Ok(BisectResult {
+            oldest_holds,
+            newest_holds,
+            last_before: None,
+            first_after: None,
+            steps,
+        });
+    }
+
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        let remaining = steps_needed(lo, hi) - 1;
+        if test_at(mid, remaining)? == oldest_holds {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    Ok(BisectResult {
+        oldest_holds,
+        newest_holds,
+        last_before: Some(toolchains[lo].clone()),
+        first_after: Some(toolchains[hi].clone()),
+        steps,
+    })
+}
+
+/// The most toolchains left to test when the change is between `lo` and
+/// `hi`; that is, the ceiling of log2 of the distance between them.
+fn steps_needed(lo: usize, hi: usize) -> usize {
+    let distance = hi - lo;
+    (usize::BITS - (distance - 1).leading_zeros()) as usize
+}
+
+/// The dated nightlies in `toolchains` that are for `host`, or have no host
+/// triple, with one toolchain for each date.
+fn native_nightlies(toolchains: Vec<String>, host: &str) -> Vec<String> {
+    let mut nightlies = toolchains
+        .into_iter()
+        .filter(|t| is_dated_nightly(t))
+        .filter(|t| t.len() == 18 || t[19..] == *host)
+        .collect::<Vec<_>>();
+    nightlies.dedup_by(|a, b| a[..18] == b[..18]);
+    nightlies
+}
+
+/// Matches `nightly-YYYY-MM-DD`, with or without a host triple.
+fn is_dated_nightly(toolchain: &str) -> bool {
+    let date = match toolchain.strip_prefix("nightly-") {
+        Some(rest) if rest.len() >= 10 => &rest.as_bytes()[..10],
+        _ => return false,
+    };
+    let is_date = date.iter().enumerate().all(|(i, b)| match i {
+        4 | 7 => *b == b'-',
+        _ => b.is_ascii_digit(),
+    });
+    is_date && (toolchain.len() == 18 || toolchain.as_bytes()[18] == b'-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nightlies(n: usize) -> Vec<String> {
+        (1..=n).map(|day| format!("nightly-2023-01-{:02}", day)).collect()
+    }
+
+    #[test]
+    fn signal_is_not_success() {
+        let runs = BisectPredicate::RunsSuccessfully;
+        assert!(runs.holds(&MatrixEntry::with_run(true, Some(true))));
+        // e.g. a segfault, which has no exit code
+        assert!(!runs.holds(&MatrixEntry::with_run(true, Some(false))));
+        assert!(runs.holds(&MatrixEntry::with_run(true, None)));
+        assert!(!runs.holds(&MatrixEntry::with_run(false, None)));
+    }
+
+    #[test]
+    fn finds_first_change() {
+        let toolchains = nightlies(20);
+        let mut events = Vec::new();
+        let result = search(
+            &toolchains,
+            |t| Ok(t >= "nightly-2023-01-13"),
+            |e| events.push(e),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+
+        assert_eq!(result.last_before.as_deref(), Some("nightly-2023-01-12"));
+        assert_eq!(result.first_after.as_deref(), Some("nightly-2023-01-13"));
+        assert!(!result.oldest_holds && result.newest_holds);
+        assert!(result.steps.len() <= steps_needed(0, 19) + 2);
+        assert_eq!(events.len(), result.steps.len() * 2);
+        assert_eq!(
+            events[0],
+            BisectProgress::Testing { toolchain: "nightly-2023-01-01".into(), remaining: 6 }
+        );
+    }
+
+    #[test]
+    fn no_change_and_cancel() {
+        let toolchains = nightlies(5);
+        let result = search(&toolchains, |_| Ok(true), |_| (), &AtomicBool::new(false)).unwrap();
+        assert_eq!(result.first_after, None);
+        assert_eq!(result.steps.len(), 2);
+
+        let cancel = AtomicBool::new(false);
+        let result = search(
+            &toolchains,
+            |_| {
+                cancel.store(true, Ordering::SeqCst);
+                Ok(true)
+            },
+            |_| (),
+            &cancel,
+        );
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(matches!(
+            search(&nightlies(1), |_| Ok(true), |_| (), &cancel),
+            Err(Error::NotEnoughNightlies(1))
+        ));
+    }
+
+    #[test]
+    fn dated_nightlies() {
+        assert!(is_dated_nightly("nightly-2019-01-26"));
+        assert!(is_dated_nightly("nightly-2019-01-26-x86_64-apple-darwin"));
+        assert!(!is_dated_nightly("nightly-x86_64-apple-darwin"));
+        assert!(!is_dated_nightly("nightly-2019-01-261"));
+        assert!(!is_dated_nightly("stable"));
+    }
+
+    #[test]
+    fn only_native_nightlies() {
+        let toolchains = vec![
+            "stable-aarch64-apple-darwin",
+            "nightly-2023-01-01-aarch64-apple-darwin",
+            "nightly-2023-01-01-x86_64-apple-darwin",
+            "nightly-2023-01-02-x86_64-apple-darwin",
+            "nightly-2023-01-03",
+            "nightly-2023-01-04-aarch64-apple-darwin",
+        ];
+        let toolchains = toolchains.into_iter().map(String::from).collect();
+        assert_eq!(
+            native_nightlies(toolchains, "aarch64-apple-darwin"),
+            vec![
+                "nightly-2023-01-01-aarch64-apple-darwin",
+                "nightly-2023-01-03",
+                "nightly-2023-01-04-aarch64-apple-darwin",
+            ]
+        );
+    }
+
+    #[test]
+    fn step_counts() {
+        assert_eq!(steps_needed(0, 1), 0);
+        assert_eq!(steps_needed(0, 2), 1);
+        assert_eq!(steps_needed(0, 4), 2);
+        assert_eq!(steps_needed(3, 8), 3);
+    }
+}
@@ -1,17 +1,34 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
-use std::io::{BufRead, BufReader};
-use std::path::{Path, PathBuf};
+use std::hash::{Hash, Hasher};
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
 
+use crate::ansi;
+use crate::deps::{resolve_dependencies, DependencyGraph};
+use crate::doc::{find_doc_indices, DocIndex};
 use crate::error::Error;
-
-#[derive(Debug, Clone, Deserialize)]
+use crate::gc::ActiveDir;
+use crate::handle::CancelToken;
+use crate::lint::LintConfig;
+use crate::output::{self, OutputCap, OutputLimit, Stream};
+use crate::progress::{BuildProgress, ProgressTracker};
+use crate::run::{run_program, RunResult};
+use crate::sandbox::{run_sandboxed, SandboxConfig};
+use crate::sanitizer::{Sanitizer, SanitizerReport};
+use crate::timings::{self, TimingsReport};
+use crate::toolchain;
+
+#[derive(Debug, Clone, Hash, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum Type {
     Run,
     Check,
     Test,
     Clean,
+    Doc,
 }
 
 impl Type {
@@ -21,17 +38,122 @@ impl Type {
             Type::Check => "build",
             Type::Test => "test",
             Type::Clean => "clean",
+            Type::Doc => "doc",
+        }
+    }
+
+    fn supports_timings(&self) -> bool {
+        !matches!(self, Type::Clean)
+    }
+}
+
+/// An optimization level, applied to whichever profile the task builds with.
+#[derive(Debug, Clone, Copy, Hash, Deserialize)]
+enum OptLevel {
+    #[serde(rename = "0")]
+    Zero,
+    #[serde(rename = "1")]
+    One,
+    #[serde(rename = "2")]
+    Two,
+    #[serde(rename = "3")]
+    Three,
+    #[serde(rename = "s")]
+    Size,
+    #[serde(rename = "z")]
+    MinSize,
+}
+
+impl OptLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            OptLevel::Zero => "0",
+            OptLevel::One => "1",
+            OptLevel::Two => "2",
+            OptLevel::Three => "3",
+            OptLevel::Size => "s",
+            OptLevel::MinSize => "z",
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Hash, Deserialize)]
 pub struct Task {
     toolchain: String,
     code: String,
     task_type: Type,
     backtrace: bool,
     release: bool,
+    /// If `true`, collect profiling data about the build.
+    #[serde(default)]
+    timings: bool,
+    /// Overrides the profile's `opt-level`, e.g. to compare binary sizes.
+    #[serde(default)]
+    opt_level: Option<OptLevel>,
+    /// If present, build with this sanitizer on the host target and run
+    /// the program. Requires a nightly toolchain.
+    #[serde(default)]
+    sanitizer: Option<Sanitizer>,
+    /// If present, `run` tasks also run the program in a sandbox with these
+    /// limits. Only supported on Linux.
+    #[serde(default)]
+    sandbox: Option<SandboxConfig>,
+    /// Caps on the output streamed and collected during this task.
+    #[serde(default)]
+    output_limit: OutputLimit,
+    /// If `true`, collect the compiler's diagnostics as json, so that their
+    /// suggestions can be applied. The rendered diagnostics are still passed
+    /// to the stderr callback, as they arrive.
+    #[serde(default)]
+    json_diagnostics: bool,
+    /// Lint levels for the snippet.
+    #[serde(default)]
+    lints: LintConfig,
+    /// If `true`, cargo's output includes ANSI colors even though it is not
+    /// writing to a terminal; see `parse_ansi`.
+    #[serde(default)]
+    color: bool,
+}
+
+impl Task {
+    /// A hash of the code, toolchain and options, used to cache results.
+    pub(crate) fn cache_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub(crate) fn is_check(&self) -> bool {
+        matches!(self.task_type, Type::Check)
+    }
+
+    pub(crate) fn is_run(&self) -> bool {
+        matches!(self.task_type, Type::Run)
+    }
+
+    pub(crate) fn backtrace(&self) -> bool {
+        self.backtrace
+    }
+
+    pub(crate) fn output_limit(&self) -> &OutputLimit {
+        &self.output_limit
+    }
+
+    /// Returns a copy of this task that uses a different toolchain.
+    pub(crate) fn with_toolchain(&self, toolchain: &str) -> Task {
+        Task { toolchain: toolchain.to_owned(), ..self.clone() }
+    }
+
+    /// `clean` tasks have no result worth reusing, and a `run` task that
+    /// runs the program itself, in the sandbox or with a sanitizer, should
+    /// run it again rather than replay its old output.
+    pub(crate) fn is_cacheable(&self) -> bool {
+        match self.task_type {
+            Type::Clean => false,
+            Type::Run => self.sandbox.is_none() && self.sanitizer.is_none(),
+            _ => true,
+        }
+    }
 }
 
 /// The result of a rustc run.
@@ -42,6 +164,65 @@ pub struct CompilerResult {
     stderr: String,
     /// The path to the produced binary, if any.
     executable: Option<PathBuf>,
+    /// Profiling data, if it was requested.
+    timings: Option<TimingsReport>,
+    /// The output of the program, if it was run as part of this task.
+    run: Option<RunResult>,
+    /// The problem reported by the sanitizer, if any.
+    sanitizer_report: Option<SanitizerReport>,
+    /// For `doc` tasks, the generated documentation for the snippet and
+    /// each of its dependencies.
+    docs: Option<Vec<DocIndex>>,
+    /// `true` if any output was cut off because it exceeded the task's
+    /// `output_limit`.
+    truncated: bool,
+    /// The json compiler messages, one per line, if `json_diagnostics` was set.
+    diagnostics: Option<String>,
+    /// The packages that were resolved for the snippet, with their exact
+    /// versions. `None` for `clean` tasks, or if resolution failed.
+    dependencies: Option<DependencyGraph>,
+}
+
+impl CompilerResult {
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    pub fn executable(&self) -> Option<&Path> {
+        self.executable.as_deref()
+    }
+
+    pub fn run(&self) -> Option<&RunResult> {
+        self.run.as_ref()
+    }
+
+    pub fn dependencies(&self) -> Option<&DependencyGraph> {
+        self.dependencies.as_ref()
+    }
+
+    /// Runs the program that was built, in `outdir`, unless the task already
+    /// ran it. Does nothing if the build failed.
+    pub(crate) fn run_executable(
+        &mut self,
+        outdir: &Path,
+        backtrace: bool,
+        limit: &OutputLimit,
+    ) -> Result<(), Error> {
+        if let (true, None, Some(executable)) = (self.success, &self.run, &self.executable) {
+            let run = run_program(executable, outdir, backtrace, limit)?;
+            self.truncated |= run.is_truncated();
+            self.run = Some(run);
+        }
+        Ok(())
+    }
 }
 
 /// Attempts to run the given task in the supplied directory, which will
@@ -49,17 +230,93 @@ pub struct CompilerResult {
 pub fn do_compile_task<P, F>(
     outdir: P,
     task: Task,
-    mut std_err_callback: F,
+    std_err_callback: F,
+) -> Result<CompilerResult, Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str),
+{
+    do_compile_task_with_progress(outdir, task, std_err_callback, |_| {})
+}
+
+/// Like `do_compile_task`, but additionally reports structured progress
+/// events parsed from cargo's output. Raw stderr lines are still passed
+/// to `std_err_callback`.
+pub fn do_compile_task_with_progress<P, F, G>(
+    outdir: P,
+    task: Task,
+    std_err_callback: F,
+    progress_callback: G,
 ) -> Result<CompilerResult, Error>
 where
     P: AsRef<Path>,
     F: FnMut(&str),
+    G: FnMut(BuildProgress),
+{
+    let mut std_err_callback = std_err_callback;
+    let output_callback = |stream, text: &str| {
+        if stream == Stream::Stderr {
+            std_err_callback(text)
+        }
+    };
+    compile_task(outdir.as_ref(), task, output_callback, progress_callback, None)
+}
+
+/// Like `do_compile_task_with_progress`, but cargo's stdout is streamed as
+/// well, with each line passed to `output_callback` along with the stream
+/// it came from. Lines are passed on in the order they were read.
+///
+/// With `json_diagnostics`, cargo's json messages are not passed on; their
+/// rendered text is passed on as stderr instead.
+pub fn do_compile_task_with_output<P, F, G>(
+    outdir: P,
+    task: Task,
+    output_callback: F,
+    progress_callback: G,
+) -> Result<CompilerResult, Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(Stream, &str),
+    G: FnMut(BuildProgress),
+{
+    compile_task(outdir.as_ref(), task, output_callback, progress_callback, None)
+}
+
+/// The implementation of `do_compile_task_with_output`. If `cancel` is
+/// cancelled, cargo is killed and this returns `Error::Cancelled`.
+pub(crate) fn compile_task<F, G>(
+    outdir: &Path,
+    task: Task,
+    mut output_callback: F,
+    mut progress_callback: G,
+    cancel: Option<&CancelToken>,
+) -> Result<CompilerResult, Error>
+where
+    F: FnMut(Stream, &str),
+    G: FnMut(BuildProgress),
 {
-    let outdir = outdir.as_ref();
-    create_cargo_scaffold(&outdir, &task.code)?;
+    let _active = ActiveDir::enter(outdir);
+    let is_cancelled = || cancel.map(CancelToken::is_cancelled).unwrap_or(false);
+    // we check sanitizer support before doing any work
+    let target = match task.sanitizer {
+        Some(sanitizer) => Some(sanitizer.validate(&task.toolchain)?),
+        None => None,
+    };
+
+    let lints = task.lints.resolve(&task.toolchain)?;
+    for warning in lints.warnings() {
+        output_callback(Stream::Stderr, warning);
+    }
+
+    create_cargo_scaffold(&outdir, &task.code, &lints.manifest_section())?;
     activate_toolchain(outdir, &task.toolchain)?;
     let mut command = Command::new("cargo");
-    command.current_dir(outdir).arg(task.task_type.as_str());
+    // clippy's lints are only checked if we run clippy itself
+    let subcommand = match task.task_type {
+        Type::Check if task.lints.uses_clippy() => "clippy",
+        ref other => other.as_str(),
+    };
+    command.current_dir(outdir).arg(subcommand);
 
     if task.backtrace {
         command.env("RUST_BACKTRACE", "1");
@@ -69,44 +326,206 @@ where
         command.arg("--release");
     }
 
+    if let Some(opt_level) = task.opt_level {
+        let var = if task.release {
+            "CARGO_PROFILE_RELEASE_OPT_LEVEL"
+        } else {
+            "CARGO_PROFILE_DEV_OPT_LEVEL"
+        };
+        command.env(var, opt_level.as_str());
+    }
+
+    let mut rustflags = Vec::new();
+    let collect_timings = task.timings && task.task_type.supports_timings();
+    if collect_timings {
+        command.arg("--timings");
+        if toolchain::is_nightly(&task.toolchain) {
+            rustflags.push("-Ztime-passes".to_string());
+        }
+    }
+
+    rustflags.extend(lints.rustflags());
+
+    if let (Some(sanitizer), Some(target)) = (task.sanitizer, target.as_ref()) {
+        // sanitizers require an explicit target, so build scripts are not instrumented
+        command.args(["--target", target]).args(sanitizer.cargo_args());
+        rustflags.extend(sanitizer.rustflags());
+    }
+
+    if task.color {
+        command.env("CARGO_TERM_COLOR", "always");
+    }
+
+    if task.json_diagnostics && task.color {
+        command.arg("--message-format=json-diagnostic-rendered-ansi");
+    } else if task.json_diagnostics {
+        command.arg("--message-format=json");
+    }
+
+    if !rustflags.is_empty() {
+        command.env("RUSTFLAGS", rustflags.join(" "));
+    }
+
     command.stderr(Stdio::piped());
     command.stdout(Stdio::piped());
+    // so that exceeding the output limit can kill the tests cargo runs, too
+    command.process_group(0);
 
+    if is_cancelled() {
+        return Err(Error::Cancelled);
+    }
     let mut child = command.spawn().map_err(Error::CompileFailed)?;
     let stderr = child.stderr.take().expect("piped stderr must exist");
-    let mut linereader = BufReader::new(stderr);
-
-    let mut line_buf = String::new();
-    // we send stderr lines as they arrive, so the client
+    let stdout = child.stdout.take().expect("piped stdout must exist");
+    let limit = task.output_limit;
+    // both pipes are drained at once, so that neither can fill up and
+    // block cargo while we wait on the other
+    let lines = output::merge_lines(stdout, stderr, limit.line_bytes());
+
+    let child = Arc::new(Mutex::new(child));
+    if let Some(cancel) = cancel {
+        cancel.register(&child);
+    }
+    let mut stderr_cap = OutputCap::new(limit.streamed_bytes());
+    let mut stdout_cap = OutputCap::new(limit.streamed_bytes());
+    let mut collected_cap = OutputCap::new(limit.collected_bytes());
+    let mut stdout = String::new();
+    let mut diagnostics = String::new();
+
+    let mut tracker = ProgressTracker::new(outdir);
+    let mut pass_lines = Vec::new();
+    let mut sanitizer_stderr = String::new();
+    // we send lines as they arrive, so the client
     // is more responsive & informative
-    loop {
-        match linereader.read_line(&mut line_buf) {
-            Ok(0) => {
-                println!("read len 0");
-                break;
+    for (stream, line) in lines {
+        if stream == Stream::Stdout {
+            if task.json_diagnostics {
+                if let Some(message) = json_message(&line) {
+                    if message["reason"] == "compiler-message" {
+                        diagnostics.push_str(line.trim_end());
+                        diagnostics.push('\n');
+                    }
+                    let rendered = message["message"]["rendered"].as_str();
+                    if let Some(text) = rendered.and_then(|text| stderr_cap.take(text)) {
+                        output_callback(Stream::Stderr, &text);
+                    }
+                    continue;
+                }
+            }
+            // only the program's own output counts towards the limit
+            if let Some(text) = collected_cap.take(&line) {
+                stdout.push_str(&text);
+                if collected_cap.is_truncated() && limit.kill_on_exceed() {
+                    output::kill_process_group(&child);
+                }
             }
-            Ok(_) => {
-                std_err_callback(&line_buf);
-                line_buf.clear();
+            if let Some(text) = stdout_cap.take(&line) {
+                output_callback(Stream::Stdout, &text);
             }
-            Err(e) => return Err(Error::CompileFailed(e)),
+            continue;
+        }
+
+        if let Some(text) = stderr_cap.take(&line) {
+            output_callback(Stream::Stderr, &text);
+            // this is the line that included the truncation marker
+            if stderr_cap.is_truncated() && limit.kill_on_exceed() {
+                output::kill_process_group(&child);
+            }
+        }
+        // colors are only for display
+        let line = if task.color { ansi::strip_ansi(&line) } else { line };
+        if let Some(event) = tracker.parse_line(&line) {
+            progress_callback(event);
+        }
+        if collect_timings && timings::is_pass_line(&line) {
+            pass_lines.push(line.clone());
+        }
+        if task.sanitizer.is_some() {
+            sanitizer_stderr.push_str(&line);
         }
     }
 
-    let output = child.wait_with_output().map_err(Error::CompileFailed)?;
-    let success = output.status.success();
-    let executable = get_output_path(outdir, &task);
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-    Ok(CompilerResult { success, stdout, stderr, executable })
+    let stdout_truncated = collected_cap.is_truncated();
+    let diagnostics = if task.json_diagnostics { Some(diagnostics) } else { None };
+    let status = child.lock().unwrap().wait().map_err(Error::CompileFailed)?;
+    if is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+    let success = status.success();
+    let executable = get_output_path(outdir, &task, target.as_deref());
+    // stderr has already been sent to the callback
+    let stderr = String::new();
+    let timings =
+        if collect_timings { Some(TimingsReport::collect(outdir, &pass_lines)) } else { None };
+
+    // `cargo test` runs the program itself; otherwise we run it here if
+    // a sanitizer or the sandbox was requested.
+    let run = match (&task.task_type, executable.as_ref()) {
+        (Type::Run, Some(exec)) if success => match (task.sandbox.as_ref(), task.sanitizer) {
+            (Some(config), _) => {
+                Some(run_sandboxed(exec, Some(outdir), task.backtrace, config, &limit)?)
+            }
+            (None, Some(_)) => Some(run_program(exec, outdir, task.backtrace, &limit)?),
+            (None, None) => None,
+        },
+        _ => None,
+    };
+
+    let sanitizer_report = task.sanitizer.filter(|_| success).and_then(|sanitizer| {
+        let report_src = run.as_ref().map(RunResult::stderr).unwrap_or(&sanitizer_stderr);
+        SanitizerReport::parse(sanitizer, report_src, outdir)
+    });
+
+    let docs = match task.task_type {
+        Type::Doc if success => Some(find_doc_indices(outdir)),
+        _ => None,
+    };
+
+    let dependencies = match task.task_type {
+        Type::Clean => None,
+        _ => resolve_dependencies(outdir, &task.toolchain),
+    };
+
+    let truncated = stderr_cap.is_truncated()
+        || stdout_truncated
+        || run.as_ref().map(RunResult::is_truncated).unwrap_or(false);
+
+    Ok(CompilerResult {
+        success,
+        stdout,
+        stderr,
+        executable,
+        timings,
+        run,
+        sanitizer_report,
+        docs,
+        truncated,
+        diagnostics,
+        dependencies,
+    })
 }
 
-fn create_cargo_scaffold(path: &Path, code: &str) -> Result<(), Error> {
+/// Parses a line of cargo's stdout as a json message, if it is one.
+fn json_message(line: &str) -> Option<serde_json::Value> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    serde_json::from_str(line).ok()
+}
+
+fn create_cargo_scaffold(path: &Path, code: &str, lints_section: &str) -> Result<(), Error> {
     let src_dir = path.join("src");
     fs::create_dir_all(&src_dir).map_err(|_| Error::CreateOutputFailed(src_dir.clone()))?;
 
-    let main_rs = src_dir.join("main.rs");
-    fs::write(&main_rs, code.as_bytes()).map_err(|_| Error::CreateOutputFailed(main_rs.clone()))?;
+    // snippets without a main function are built as libraries
+    let (source, stale) =
+        if has_main_fn(code) { ("main.rs", "lib.rs") } else { ("lib.rs", "main.rs") };
+    let source = src_dir.join(source);
+    fs::write(&source, code.as_bytes()).map_err(|_| Error::CreateOutputFailed(source.clone()))?;
+    let stale = src_dir.join(stale);
+    if stale.exists() {
+        fs::remove_file(&stale).map_err(|_| Error::CreateOutputFailed(stale))?;
+    }
 
     let cargo_toml = path.join("Cargo.toml");
     let extra_deps = parse_dep_comments(code)?;
@@ -116,6 +535,7 @@ fn create_cargo_scaffold(path: &Path, code: &str) -> Result<(), Error> {
         manifest.push_str(&line);
         manifest.push('\n');
     }
+    manifest.push_str(lints_section);
 
     fs::write(&cargo_toml, manifest.as_bytes())
         .map_err(|_| Error::CreateOutputFailed(cargo_toml))?;
@@ -123,6 +543,14 @@ fn create_cargo_scaffold(path: &Path, code: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Returns `true` if the snippet appears to declare a `main` function.
+fn has_main_fn(code: &str) -> bool {
+    code.lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with("//"))
+        .any(|l| l.contains("fn main(") || l.contains("fn main ("))
+}
+
 /// Hacky. We allow dependencies to be specified as comments with the form,
 /// '//~ serde = "1.0"'. This finds those strings and returns them formatted
 /// suitable for appending to the toml text.
@@ -130,27 +558,50 @@ fn parse_dep_comments(code: &str) -> Result<Vec<String>, Error> {
     code.lines().filter(|l| l.trim().starts_with("//~")).map(dep_for_comment_line).collect()
 }
 
+/// The names of the crates in `code`'s `//~ use` lines, skipping any that
+/// are malformed.
+pub(crate) fn inline_dependency_names(code: &str) -> Vec<&str> {
+    code.lines()
+        .filter(|l| l.trim().starts_with("//~"))
+        .filter(|l| dep_for_comment_line(l).is_ok())
+        .filter_map(|l| l.trim().trim_start_matches("//~").split_whitespace().nth(1))
+        .collect()
+}
+
 fn dep_for_comment_line(line: &str) -> Result<String, Error> {
     // we trim twice to get whitespace between thee comment markere and the first token
     let line = line.trim().trim_start_matches("//~").trim();
     let tokens = line.split_whitespace().collect::<Vec<_>>();
-    match tokens.as_slice() {
-        &["use", name] => {
-            if name.chars().all(legal_in_crate_name) {
-                Ok(format!("{} = \"*\"", name))
-            } else {
-                Err(Error::MalformedDependency(line.to_owned()))
-            }
+    // the features come last, so that a crate may be named `features`
+    let (name, version, features) = match *tokens.as_slice() {
+        ["use", name] => (name, "*", None),
+        ["use", name, "=", version] => (name, version.trim_matches('"'), None),
+        ["use", name, "features", "=", features] => (name, "*", Some(features)),
+        ["use", name, "=", version, "features", "=", features] => {
+            (name, version.trim_matches('"'), Some(features))
         }
-        &["use", name, "=", version] => {
-            let version = version.trim_matches('"');
-            if name.chars().all(legal_in_crate_name) && version.chars().all(legal_in_version) {
-                Ok(format!("{} = \"{}\"", name, version))
-            } else {
-                Err(Error::MalformedDependency(line.into()))
+        _ => return Err(Error::MalformedDependency(line.into())),
+    };
+    let version_ok = version == "*" || version.chars().all(legal_in_version);
+    if !name.chars().all(legal_in_crate_name) || !version_ok {
+        return Err(Error::MalformedDependency(line.into()));
+    }
+
+    match features {
+        None => Ok(format!("{} = \"{}\"", name, version)),
+        Some(features) => {
+            let features = features.split(',').filter(|f| !f.is_empty()).collect::<Vec<_>>();
+            if features.is_empty() || !features.iter().all(|f| f.chars().all(legal_in_feature)) {
+                return Err(Error::MalformedDependency(line.into()));
             }
+            let features = features.iter().map(|f| format!("\"{}\"", f)).collect::<Vec<_>>();
+            Ok(format!(
+                "{} = {{ version = \"{}\", features = [{}] }}",
+                name,
+                version,
+                features.join(", ")
+            ))
         }
-        _other => Err(Error::MalformedDependency(line.into())),
     }
 }
 
@@ -162,7 +613,17 @@ fn legal_in_version(c: char) -> bool {
     matches!(c, '0'..='9' | '.')
 }
 
+fn legal_in_feature(c: char) -> bool {
+    legal_in_crate_name(c) || c == '/'
+}
+
+/// Held while running `rustup override`, which rewrites rustup's settings
+/// file; concurrent overrides for different directories can otherwise be
+/// lost, and a build silently use the wrong toolchain.
+pub(crate) static OVERRIDE_LOCK: Mutex<()> = Mutex::new(());
+
 fn activate_toolchain(path: &Path, toolchain: &str) -> Result<(), Error> {
+    let _guard = OVERRIDE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let result = Command::new("rustup")
         .current_dir(path)
         .args(&["override", "set", toolchain])
@@ -176,8 +637,69 @@ fn activate_toolchain(path: &Path, toolchain: &str) -> Result<(), Error> {
     }
 }
 
-fn get_output_path(path: &Path, task: &Task) -> Option<PathBuf> {
+/// Maps a line in a source file of the generated crate to a line in the
+/// snippet. The snippet is written to `src/main.rs` or `src/lib.rs`
+/// verbatim, so lines in those files map directly.
+///
+/// Absolute paths are only mapped if they are in `outdir`, as dependencies
+/// and std have files with the same names.
+pub(crate) fn snippet_line_for_source(
+    file: &str,
+    line: usize,
+    outdir: Option<&Path>,
+) -> Option<usize> {
+    if is_snippet_source(Path::new(file), outdir) {
+        Some(line)
+    } else {
+        None
+    }
+}
+
+/// Whether `file` is the snippet's source file, given either relative to
+/// the generated crate, e.g. `./src/main.rs`, or as a path in `outdir`.
+pub(crate) fn is_snippet_source(file: &Path, outdir: Option<&Path>) -> bool {
+    let relative = if file.is_absolute() {
+        // canonicalized, where they exist, so that symlinks such as
+        // `/tmp -> /private/tmp` do not matter
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let outdir = match outdir {
+            Some(outdir) => canonical(outdir),
+            None => return false,
+        };
+        match canonical(file).strip_prefix(&outdir) {
+            Ok(relative) => relative.to_owned(),
+            Err(_) => return false,
+        }
+    } else {
+        file.components().filter(|c| *c != Component::CurDir).collect()
+    };
+    relative == Path::new("src/main.rs") || relative == Path::new("src/lib.rs")
+}
+
+/// Maps a byte offset in a source file of the generated crate to an offset
+/// in the snippet. Dependency comments (`//~ use ...`) are left in place,
+/// so offsets in `src/main.rs` or `src/lib.rs` map directly, as long as they
+/// fall on a character boundary in the snippet.
+///
+/// rustc gives the crate's own files relative to it, and those of
+/// dependencies as absolute paths, so only relative paths are mapped.
+pub(crate) fn snippet_offset_for_source(code: &str, file: &Path, offset: usize) -> Option<usize> {
+    if !is_snippet_source(file, None) {
+        return None;
+    }
+    if code.is_char_boundary(offset) {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+fn get_output_path(path: &Path, task: &Task, target: Option<&str>) -> Option<PathBuf> {
     let path = path.join("target");
+    let path = match target {
+        Some(target) => path.join(target),
+        None => path,
+    };
     let path = if task.release { path.join("release") } else { path.join("debug") };
     let path = path.join(BIN_TARGET_NAME);
     if path.exists() {
@@ -187,7 +709,20 @@ fn get_output_path(path: &Path, task: &Task) -> Option<PathBuf> {
     }
 }
 
-const BIN_TARGET_NAME: &str = "playground";
+pub(crate) const BIN_TARGET_NAME: &str = "playground";
+
+/// Whether `manifest` is one we generated, by its package name.
+pub(crate) fn is_scaffold_manifest(manifest: &str) -> bool {
+    let mut in_package = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+        } else if in_package && line.replace(' ', "") == "name=\"playground\"" {
+            return true;
+        }
+    }
+    false
+}
 
 static PLACEHOLDER_CARGO_TOML: &str = r#"
 [package]
@@ -214,12 +749,76 @@ mod tests {
             task_type: Type::Run,
             backtrace: true,
             release: false,
+            timings: false,
+            opt_level: None,
+            sanitizer: None,
+            sandbox: None,
+            output_limit: OutputLimit::default(),
+            json_diagnostics: false,
+            lints: LintConfig::default(),
+            color: false,
         };
 
         let exp_exec_path = outdir.join("target").join("debug").join(BIN_TARGET_NAME);
         let result = do_compile_task(&outdir, task, |_| {}).expect("compile task failed");
 
         assert_eq!(result.executable, Some(exp_exec_path));
+        let dependencies = result.dependencies.expect("dependencies were not resolved");
+        assert_eq!(dependencies.root().name(), "playground");
+        assert_eq!(dependencies.packages().len(), 1);
+    }
+
+    #[test]
+    fn colored_output() {
+        let tempdir = TempDir::new("colored_output").expect("failed to create temp dir");
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "toolchain": "stable",
+            "code": "fn main() { let x = 1; }",
+            "task_type": "check",
+            "backtrace": false,
+            "release": false,
+            "color": true,
+        }))
+        .unwrap();
+
+        let mut stderr = String::new();
+        let mut progress = Vec::new();
+        let result = do_compile_task_with_progress(
+            tempdir.path(),
+            task,
+            |text| stderr.push_str(text),
+            |event| progress.push(event),
+        )
+        .expect("compile task failed");
+
+        assert!(result.success);
+        assert!(stderr.contains("\u{1b}["));
+        assert!(crate::parse_ansi(&stderr).text().contains("warning: unused variable: `x`"));
+        // progress is still parsed from the colored lines
+        assert!(!progress.is_empty());
+    }
+
+    #[test]
+    fn json_messages_are_not_capped() {
+        let tempdir = TempDir::new("json_messages").expect("failed to create temp dir");
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "toolchain": "stable",
+            "code": "fn main() { let x = 1; let y = 2; }",
+            "task_type": "check",
+            "backtrace": false,
+            "release": false,
+            "json_diagnostics": true,
+            "output_limit": { "collected_bytes": 16, "kill_on_exceed": true },
+        }))
+        .unwrap();
+
+        let result = do_compile_task(tempdir.path(), task, |_| {}).expect("compile task failed");
+
+        // cargo's messages are far longer than the limit, but were neither
+        // truncated nor caused cargo to be killed
+        assert!(result.success);
+        assert!(!result.truncated);
+        assert_eq!(result.diagnostics.as_deref().map(|d| d.lines().count()), Some(2));
     }
 
     #[test]
@@ -239,5 +838,59 @@ mod tests {
         // identifiers are alphanums
         assert!(dep_for_comment_line("//~ use jsoñ = \"5\"").is_err());
         assert!(dep_for_comment_line("//~ use jso.n = \"5\"").is_err());
+
+        assert_eq!(
+            dep_for_comment_line("//~ use tokio = 1 features = macros,rt-multi-thread").unwrap(),
+            "tokio = { version = \"1\", features = [\"macros\", \"rt-multi-thread\"] }"
+        );
+        assert_eq!(
+            dep_for_comment_line("//~ use serde features = derive").unwrap(),
+            "serde = { version = \"*\", features = [\"derive\"] }"
+        );
+        assert!(dep_for_comment_line("//~ use serde features =").is_err());
+        assert_eq!(dep_for_comment_line("//~ use features = 1").unwrap(), "features = \"1\"");
+        assert_eq!(
+            dep_for_comment_line("//~ use features features = std").unwrap(),
+            "features = { version = \"*\", features = [\"std\"] }"
+        );
+
+        // an explicit `*` is allowed, and means the same as no version
+        assert_eq!(dep_for_comment_line("//~ use ast = *").unwrap(), "ast = \"*\"");
+        assert_eq!(dep_for_comment_line("//~ use ast = \"*\"").unwrap(), "ast = \"*\"");
+        assert!(dep_for_comment_line("//~ use ast = 1.*").is_err());
+        assert!(dep_for_comment_line("//~ use serde = 1 features = a\"b").is_err());
+    }
+
+    #[test]
+    fn scaffold_manifest() {
+        assert!(is_scaffold_manifest(PLACEHOLDER_CARGO_TOML));
+        assert!(!is_scaffold_manifest("[package]\nname = \"mine\"\n"));
+        assert!(!is_scaffold_manifest(
+            "[package]\nname = \"a\"\n[dependencies.x]\nname = \"playground\""
+        ));
+    }
+
+    #[test]
+    fn snippet_sources() {
+        let outdir = Path::new("/tmp/out");
+        assert!(is_snippet_source(Path::new("src/main.rs"), None));
+        assert!(is_snippet_source(Path::new("./src/lib.rs"), None));
+        assert!(is_snippet_source(Path::new("/tmp/out/src/main.rs"), Some(outdir)));
+        assert!(!is_snippet_source(Path::new("/tmp/out/src/main.rs"), None));
+        assert!(!is_snippet_source(Path::new("/tmp/other/src/main.rs"), Some(outdir)));
+        assert!(!is_snippet_source(Path::new("src/bin/main.rs"), None));
+        let registry =
+            "/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/itoa-1.0.9/src/lib.rs";
+        assert!(!is_snippet_source(Path::new(registry), Some(outdir)));
+        let std = "/rustc/abc/library/std/src/lib.rs";
+        assert!(!is_snippet_source(Path::new(std), Some(outdir)));
+    }
+
+    #[test]
+    fn detect_main() {
+        assert!(has_main_fn("fn main() {\n}"));
+        assert!(has_main_fn("#[tokio::main]\nasync fn main() {}"));
+        assert!(!has_main_fn("pub fn add(a: i32, b: i32) -> i32 { a + b }"));
+        assert!(!has_main_fn("// fn main() {}\npub struct Foo;"));
     }
 }
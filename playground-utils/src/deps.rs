@@ -0,0 +1,303 @@
+//! The resolved dependency graph of a snippet, so users can see which
+//! versions were actually built, and pin them.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use serde_json::Value;
+
+use crate::lockfile::LockedPackage;
+use crate::toolchain;
+
+/// Identifies a package in the graph.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct PackageId {
+    name: String,
+    version: String,
+}
+
+/// A package in the dependency graph.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolvedPackage {
+    name: String,
+    version: String,
+    /// Where the package came from, e.g. the crates.io registry; `None` for
+    /// the snippet itself.
+    source: Option<String>,
+    /// The features enabled when building this package. Empty if the graph
+    /// was read from the lockfile alone.
+    features: Vec<String>,
+    license: Option<String>,
+    dependencies: Vec<PackageId>,
+}
+
+/// Every package that was built for a snippet, and how they depend on each
+/// other.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DependencyGraph {
+    /// The snippet's own package.
+    root: PackageId,
+    /// All packages, including the root, sorted by name.
+    packages: Vec<ResolvedPackage>,
+}
+
+impl DependencyGraph {
+    pub fn root(&self) -> &PackageId {
+        &self.root
+    }
+
+    pub fn packages(&self) -> &[ResolvedPackage] {
+        &self.packages
+    }
+}
+
+impl PackageId {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+impl ResolvedPackage {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+/// Returns the dependency graph of the package in `outdir`, once it has
+/// been built with `toolchain`. Features and licenses come from `cargo
+/// metadata`, which leaves out packages that are only used on other
+/// platforms; if the snippet has no dependencies, or that fails, the
+/// lockfile is used alone.
+pub(crate) fn resolve_dependencies(outdir: &Path, toolchain: &str) -> Option<DependencyGraph> {
+    let locked = LockedPackage::read_lockfile(outdir);
+    if locked.len() > 1 {
+        if let Some(graph) = read_metadata(outdir, toolchain) {
+            return Some(graph);
+        }
+    }
+    from_lockfile(&locked)
+}
+
+fn read_metadata(outdir: &Path, toolchain: &str) -> Option<DependencyGraph> {
+    let host = toolchain::host_triple(toolchain).ok()?;
+    // the build has already fetched and locked everything we need
+    let output = Command::new("cargo")
+        .current_dir(outdir)
+        .args(["metadata", "--format-version", "1", "--offline", "--locked"])
+        .args(["--filter-platform", &host])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let metadata = serde_json::from_slice(&output.stdout).ok()?;
+    parse_metadata(&metadata)
+}
+
+fn parse_metadata(metadata: &Value) -> Option<DependencyGraph> {
+    let str_field = |value: &Value, key: &str| value[key].as_str().map(str::to_owned);
+    let mut ids = HashMap::new();
+    let mut info = HashMap::new();
+    for package in metadata["packages"].as_array()? {
+        let id = package["id"].as_str()?;
+        let name = str_field(package, "name")?;
+        let version = str_field(package, "version")?;
+        ids.insert(id, PackageId { name, version });
+        info.insert(id, (str_field(package, "source"), str_field(package, "license")));
+    }
+
+    let resolve = &metadata["resolve"];
+    let root = ids.get(resolve["root"].as_str()?)?.clone();
+    let mut packages = Vec::new();
+    for node in resolve["nodes"].as_array()? {
+        let id = node["id"].as_str()?;
+        let PackageId { name, version } = ids.get(id)?.clone();
+        let (source, license) = info.remove(id).unwrap_or_default();
+        let features = node["features"]
+            .as_array()
+            .map(|f| f.iter().filter_map(|f| f.as_str().map(str::to_owned)).collect())
+            .unwrap_or_default();
+        let dependencies = node["deps"]
+            .as_array()
+            .map(|deps| {
+                let dep_ids = deps.iter().filter_map(|dep| dep["pkg"].as_str());
+                dep_ids.filter_map(|id| ids.get(id).cloned()).collect()
+            })
+            .unwrap_or_default();
+        packages.push(ResolvedPackage { name, version, source, features, license, dependencies });
+    }
+
+    sort_packages(&mut packages);
+    Some(DependencyGraph { root, packages })
+}
+
+fn from_lockfile(locked: &[LockedPackage]) -> Option<DependencyGraph> {
+    let root = locked.iter().find(|p| p.source().is_none())?;
+    let root = PackageId { name: root.name().to_owned(), version: root.version().to_owned() };
+    let mut packages = locked
+        .iter()
+        .map(|package| {
+            let dependencies = package
+                .dependencies()
+                .iter()
+                .filter_map(|dep| resolve_lockfile_dependency(locked, dep))
+                .collect();
+            ResolvedPackage {
+                name: package.name().to_owned(),
+                version: package.version().to_owned(),
+                source: package.source().map(str::to_owned),
+                features: Vec::new(),
+                license: None,
+                dependencies,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    sort_packages(&mut packages);
+    Some(DependencyGraph { root, packages })
+}
+
+/// Lockfile dependencies only include the version if there is more than
+/// one version of the package.
+fn resolve_lockfile_dependency(locked: &[LockedPackage], dep: &str) -> Option<PackageId> {
+    let mut split = dep.splitn(2, ' ');
+    let name = split.next()?;
+    let version = split.next();
+    let package = locked
+        .iter()
+        .find(|p| p.name() == name && version.map(|v| v == p.version()).unwrap_or(true))?;
+    Some(PackageId { name: name.to_owned(), version: package.version().to_owned() })
+}
+
+/// Versions of the same package are left in the order cargo listed them,
+/// which is by version; comparing them as strings would put 0.10 before 0.9.
+fn sort_packages(packages: &mut [ResolvedPackage]) {
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn id(name: &str, version: &str) -> PackageId {
+        PackageId { name: name.into(), version: version.into() }
+    }
+
+    #[test]
+    fn graph_from_metadata() {
+        let registry = "registry+https://github.com/rust-lang/crates.io-index";
+        let metadata = json!({
+            "packages": [
+                {
+                    "id": "playground 0.0.0 (path+file:///tmp/out)",
+                    "name": "playground",
+                    "version": "0.0.0",
+                    "source": null,
+                    "license": null,
+                },
+                {
+                    "id": "itoa 1.0.1 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "name": "itoa",
+                    "version": "1.0.1",
+                    "source": registry,
+                    "license": "MIT OR Apache-2.0",
+                },
+            ],
+            "resolve": {
+                "root": "playground 0.0.0 (path+file:///tmp/out)",
+                "nodes": [
+                    {
+                        "id": "playground 0.0.0 (path+file:///tmp/out)",
+                        "deps": [{
+                            "name": "itoa",
+                            "pkg": "itoa 1.0.1 (registry+https://github.com/rust-lang/crates.io-index)",
+                        }],
+                        "features": [],
+                    },
+                    {
+                        "id": "itoa 1.0.1 (registry+https://github.com/rust-lang/crates.io-index)",
+                        "deps": [],
+                        "features": ["std"],
+                    },
+                ],
+            },
+        });
+
+        let graph = parse_metadata(&metadata).unwrap();
+        assert_eq!(graph.root(), &id("playground", "0.0.0"));
+        assert_eq!(
+            graph.packages(),
+            &[
+                ResolvedPackage {
+                    name: "itoa".into(),
+                    version: "1.0.1".into(),
+                    source: Some(registry.into()),
+                    features: vec!["std".into()],
+                    license: Some("MIT OR Apache-2.0".into()),
+                    dependencies: vec![],
+                },
+                ResolvedPackage {
+                    name: "playground".into(),
+                    version: "0.0.0".into(),
+                    source: None,
+                    features: vec![],
+                    license: None,
+                    dependencies: vec![id("itoa", "1.0.1")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lockfile_dependency_versions() {
+        let dir = tempdir::TempDir::new("deps").unwrap();
+        let lockfile = r#"
+[[package]]
+name = "playground"
+version = "0.0.0"
+dependencies = [
+ "rand 0.6.5",
+ "rand 0.7.3",
+ "itoa",
+]
+
+[[package]]
+name = "itoa"
+version = "1.0.1"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.6.5"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "rand"
+version = "0.7.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+        std::fs::write(dir.path().join("Cargo.lock"), lockfile).unwrap();
+        let locked = LockedPackage::read_lockfile(dir.path());
+        let graph = from_lockfile(&locked).unwrap();
+        let root = graph.packages().iter().find(|p| p.name() == "playground").unwrap();
+        assert_eq!(
+            root.dependencies,
+            vec![id("rand", "0.6.5"), id("rand", "0.7.3"), id("itoa", "1.0.1")]
+        );
+        let names = graph.packages().iter().map(|p| (p.name(), p.version())).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![("itoa", "1.0.1"), ("playground", "0.0.0"), ("rand", "0.6.5"), ("rand", "0.7.3")]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Locating the documentation generated by `cargo doc`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::compile::BIN_TARGET_NAME;
+use crate::lockfile::LockedPackage;
+
+/// The generated documentation for a single crate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocIndex {
+    crate_name: String,
+    /// The version that was documented, if it could be determined.
+    version: Option<String>,
+    /// The path to the crate's `index.html`.
+    path: PathBuf,
+}
+
+/// Returns the documentation generated in `outdir`, with the playground
+/// crate first, followed by its dependencies in alphabetical order.
+pub(crate) fn find_doc_indices(outdir: &Path) -> Vec<DocIndex> {
+    let doc_dir = outdir.join("target").join("doc");
+    let packages = LockedPackage::read_lockfile(outdir);
+
+    let entries = match fs::read_dir(&doc_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut indices = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path().join("index.html");
+            let crate_name = entry.file_name().into_string().ok()?;
+            // `src` holds rendered source files; it also has no index.html.
+            if crate_name == "src" || !path.is_file() {
+                return None;
+            }
+            let version = packages
+                .iter()
+                .find(|p| p.name().replace('-', "_") == crate_name)
+                .map(|p| p.version().to_owned());
+            Some(DocIndex { crate_name, version, path })
+        })
+        .collect::<Vec<_>>();
+
+    indices.sort_by(|a, b| {
+        (a.crate_name != BIN_TARGET_NAME, &a.crate_name)
+            .cmp(&(b.crate_name != BIN_TARGET_NAME, &b.crate_name))
+    });
+    indices
+}
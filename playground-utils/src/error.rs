@@ -1,9 +1,15 @@
+use std::cell::RefCell;
 use std::fmt;
 use std::io;
 use std::path::PathBuf;
 use std::process::Output;
 
 use ffi_support::{ErrorCode, ExternError};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+use crate::run::RunResult;
+use crate::sandbox::SandboxLimit;
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,8 +19,39 @@ pub enum Error {
     CompileFailed(io::Error),
     ToolchainSelectFailed(io::Error),
     CreateOutputFailed(PathBuf),
+    ReadOutputFailed(PathBuf),
     MalformedDependency(String),
-    BadExit(String),
+    /// A command we ran failed. `message` describes what we were doing.
+    BadExit {
+        message: String,
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    ReadBinaryFailed(PathBuf),
+    BinaryParseFailed(String),
+    UnsupportedSanitizer(String),
+    RunFailed(io::Error),
+    Superseded,
+    TaskAborted,
+    SandboxLimitExceeded(SandboxLimit, Box<RunResult>),
+    SandboxUnavailable,
+    UnknownErrorCode(String),
+    NotEnoughNightlies(usize),
+    UnknownCrate(String),
+    UnknownTemplate(String),
+    /// A template placeholder with no default was given no value. Holds the
+    /// template and placeholder names.
+    MissingPlaceholder(String, String),
+    Cancelled,
+    /// An argument passed across the FFI was null, not utf8, or not valid
+    /// json of the expected shape.
+    InvalidInput(String),
+}
+
+thread_local! {
+    /// The details of the last error converted to an `ExternError` on this
+    /// thread, as json.
+    static LAST_ERROR_DETAILS: RefCell<Option<String>> = const { RefCell::new(None) };
 }
 
 impl Error {
@@ -23,30 +60,122 @@ impl Error {
     pub fn bad_output(prelude: &str, output: &Output) -> Self {
         debug_assert!(!output.status.success());
 
-        let mut err_string = String::from(prelude);
-        if let Some(code) = output.status.code() {
-            err_string.push_str(&format!(" Exit code {}.", code));
+        Error::BadExit {
+            message: prelude.to_owned(),
+            exit_code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
         }
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        if !stderr.is_empty() {
-            err_string.pop();
-            err_string.push_str(&format!(", '{}'.", stderr));
-        }
-        Error::BadExit(err_string)
     }
 
-    /// An error code included in the json if this is sent to core.
-    /// This is not systematic. We just want to be able to easily identify
-    /// certain cases, such as when Rustup is not installed.
+    /// A failure with only a message, for when there is no process output.
+    pub fn bad_exit<S: Into<String>>(message: S) -> Self {
+        Error::BadExit { message: message.into(), exit_code: None, stderr: String::new() }
+    }
+
+    /// A stable code identifying the kind of error, included wherever an
+    /// error is reported. Codes are never reused:
+    ///
+    /// | code | error |
+    /// | ---- | ----- |
+    /// | 1  | `BadExit` |
+    /// | 2  | reserved; formerly used for any other error |
+    /// | 10 | `MissingRustup` |
+    /// | 11 | `ToolchainParseError` |
+    /// | 12 | `ReadingToolchainsDir` |
+    /// | 13 | `ToolchainSelectFailed` |
+    /// | 20 | `CompileFailed` |
+    /// | 21 | `CreateOutputFailed` |
+    /// | 22 | `ReadOutputFailed` |
+    /// | 30 | `MalformedDependency` |
+    /// | 40 | `UnknownErrorCode` |
+    /// | 41 | `ReadBinaryFailed` |
+    /// | 42 | `BinaryParseFailed` |
+    /// | 43 | `NotEnoughNightlies` |
+    /// | 44 | `UnknownCrate` |
+    /// | 45 | `UnknownTemplate` |
+    /// | 46 | `MissingPlaceholder` |
+    /// | 50 | `Superseded` |
+    /// | 51 | `Cancelled` |
+    /// | 52 | `TaskAborted` |
+    /// | 60 | `SandboxLimitExceeded` |
+    /// | 61 | `SandboxUnavailable` |
+    /// | 62 | `RunFailed` |
+    /// | 63 | `UnsupportedSanitizer` |
+    /// | 70 | `InvalidInput` |
     pub fn error_code(&self) -> i32 {
         use Error::*;
         match self {
-            BadExit(_) => 1,
+            BadExit { .. } => 1,
             MissingRustup => 10,
+            ToolchainParseError(_) => 11,
+            ReadingToolchainsDir => 12,
+            ToolchainSelectFailed(_) => 13,
+            CompileFailed(_) => 20,
+            CreateOutputFailed(_) => 21,
+            ReadOutputFailed(_) => 22,
             MalformedDependency(_) => 30,
-            _ => 2, // catchall; we can add these as we need them.
+            UnknownErrorCode(_) => 40,
+            ReadBinaryFailed(_) => 41,
+            BinaryParseFailed(_) => 42,
+            NotEnoughNightlies(_) => 43,
+            UnknownCrate(_) => 44,
+            UnknownTemplate(_) => 45,
+            MissingPlaceholder(..) => 46,
+            Superseded => 50,
+            Cancelled => 51,
+            TaskAborted => 52,
+            SandboxLimitExceeded(..) => 60,
+            SandboxUnavailable => 61,
+            RunFailed(_) => 62,
+            UnsupportedSanitizer(_) => 63,
+            InvalidInput(_) => 70,
         }
     }
+
+    /// The error as json: its `code` and `message`, along with any fields
+    /// specific to the kind of error.
+    pub fn details(&self) -> Value {
+        use Error::*;
+        let fields = match self {
+            ToolchainParseError(toolchain) => json!({ "toolchain": toolchain }),
+            CompileFailed(e) | ToolchainSelectFailed(e) | RunFailed(e) => {
+                json!({ "io_error": e.to_string() })
+            }
+            CreateOutputFailed(path) | ReadOutputFailed(path) | ReadBinaryFailed(path) => {
+                json!({ "path": path })
+            }
+            MalformedDependency(line) => json!({ "line": line }),
+            BadExit { exit_code, stderr, .. } => {
+                json!({ "exit_code": exit_code, "stderr": stderr })
+            }
+            BinaryParseFailed(reason) | UnsupportedSanitizer(reason) | InvalidInput(reason) => {
+                json!({ "reason": reason })
+            }
+            SandboxLimitExceeded(limit, run) => json!({ "limit": limit, "run": run }),
+            UnknownErrorCode(code) => json!({ "error_code": code }),
+            NotEnoughNightlies(installed) => json!({ "installed": installed }),
+            UnknownCrate(name) => json!({ "crate": name }),
+            UnknownTemplate(name) => json!({ "template": name }),
+            MissingPlaceholder(template, placeholder) => {
+                json!({ "template": template, "placeholder": placeholder })
+            }
+            MissingRustup | ReadingToolchainsDir | Superseded | TaskAborted
+            | SandboxUnavailable | Cancelled => json!({}),
+        };
+
+        let mut details = json!({ "code": self.error_code(), "message": self.to_string() });
+        if let (Some(details), Value::Object(fields)) = (details.as_object_mut(), fields) {
+            details.extend(fields);
+        }
+        details
+    }
+}
+
+/// Returns the details of the last error converted to an `ExternError` on
+/// this thread, as json, so that they can be fetched across the FFI after
+/// a call fails.
+pub fn take_last_error_details() -> Option<String> {
+    LAST_ERROR_DETAILS.with(|details| details.borrow_mut().take())
 }
 
 impl fmt::Display for Error {
@@ -59,13 +188,49 @@ impl fmt::Display for Error {
             CreateOutputFailed(p) => {
                 write!(f, "Failed to create output path at '{}'.", p.to_string_lossy())
             }
+            ReadOutputFailed(p) => {
+                write!(f, "Failed to read output path at '{}'.", p.to_string_lossy())
+            }
             CompileFailed(s) => write!(f, "Compiler command failed: '{}'.", s),
             ToolchainSelectFailed(s) => write!(f, "Toolchain select failed: '{}'.", s),
-            BadExit(msg) => write!(f, "{}", msg),
+            BadExit { message, exit_code, stderr } => {
+                let mut text = message.clone();
+                if let Some(code) = exit_code {
+                    text.push_str(&format!(" Exit code {}.", code));
+                }
+                if !stderr.is_empty() {
+                    text.pop();
+                    text.push_str(&format!(", '{}'.", stderr));
+                }
+                write!(f, "{}", text)
+            }
+            ReadBinaryFailed(p) => write!(f, "Failed to read binary at '{}'.", p.to_string_lossy()),
+            BinaryParseFailed(s) => write!(f, "Failed to parse binary: '{}'.", s),
+            UnsupportedSanitizer(s) => write!(f, "Cannot use sanitizer: {}.", s),
+            RunFailed(s) => write!(f, "Failed to run program: '{}'.", s),
+            Superseded => write!(f, "Task was superseded by a newer task."),
+            TaskAborted => write!(f, "Task was aborted before completing."),
+            SandboxLimitExceeded(limit, _) => {
+                write!(f, "Program exceeded the sandbox {} limit.", limit)
+            }
+            SandboxUnavailable => write!(f, "Sandboxing is not supported on this platform."),
+            UnknownErrorCode(code) => write!(f, "'{}' is not a known error code.", code),
+            NotEnoughNightlies(n) => {
+                write!(f, "Bisecting needs at least two dated nightlies; {} installed.", n)
+            }
+            UnknownCrate(name) => {
+                write!(f, "'{}' is not in the local registry index cache.", name)
+            }
+            UnknownTemplate(name) => write!(f, "There is no template named '{}'.", name),
+            MissingPlaceholder(template, placeholder) => {
+                write!(f, "Template '{}' needs a value for '{}'.", template, placeholder)
+            }
+            Cancelled => write!(f, "Task was cancelled."),
+            InvalidInput(s) => write!(f, "Invalid input: {}.", s),
             MalformedDependency(s) => write!(
                 f,
                 "Malformed dependency '{}'. Inline dependencies must \n\
-                 be in the form, 'use crate_name [= x[.y.z]]'.",
+                 be in the form, 'use crate_name [= x[.y.z]] [features = a,b]'.",
                 s
             ),
         }
@@ -74,9 +239,54 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.details().serialize(serializer)
+    }
+}
+
 impl From<Error> for ExternError {
     fn from(e: Error) -> ExternError {
+        let details = e.details().to_string();
+        LAST_ERROR_DETAILS.with(|last| *last.borrow_mut() = Some(details));
         let code = ErrorCode::new(e.error_code());
         ExternError::new_error(code, e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn bad_exit_details() {
+        let output = Output {
+            status: ExitStatus::from_raw(3 << 8),
+            stdout: Vec::new(),
+            stderr: b"no such command".to_vec(),
+        };
+        let error = Error::bad_output("Rustup failed.", &output);
+        assert_eq!(error.to_string(), "Rustup failed. Exit code 3, 'no such command'.");
+        assert_eq!(
+            error.details(),
+            json!({
+                "code": 1,
+                "message": "Rustup failed. Exit code 3, 'no such command'.",
+                "exit_code": 3,
+                "stderr": "no such command",
+            })
+        );
+    }
+
+    #[test]
+    fn details_across_ffi() {
+        let error = Error::CreateOutputFailed(PathBuf::from("/tmp/out"));
+        let _ = ExternError::from(error);
+        let details: Value = serde_json::from_str(&take_last_error_details().unwrap()).unwrap();
+        assert_eq!(details["code"], 21);
+        assert_eq!(details["path"], "/tmp/out");
+        assert!(take_last_error_details().is_none());
+    }
+}
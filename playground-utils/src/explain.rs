@@ -0,0 +1,75 @@
+//! Looking up the extended documentation for rustc error codes.
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+
+lazy_static! {
+    /// Explanations keyed by toolchain and error code. Each toolchain has
+    /// its own entries, since explanations change between releases.
+    static ref EXPLANATIONS: Mutex<HashMap<(String, String), String>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the markdown text of `rustc --explain` for `code`, e.g. `E0382`,
+/// using the given toolchain.
+pub fn explain_error(toolchain: &str, code: &str) -> Result<String, Error> {
+    let code = normalize_code(code).ok_or_else(|| Error::UnknownErrorCode(code.to_owned()))?;
+    let key = (toolchain.to_owned(), code);
+    if let Some(text) = EXPLANATIONS.lock().unwrap().get(&key) {
+        return Ok(text.clone());
+    }
+
+    let output = Command::new("rustup")
+        .args(["run", toolchain, "rustc", "--explain", &key.1])
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("is not a valid error code")
+            || stderr.contains("no extended information")
+        {
+            return Err(Error::UnknownErrorCode(key.1));
+        }
+        return Err(Error::bad_output("Failed to explain error code", &output));
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout).into_owned();
+    EXPLANATIONS.lock().unwrap().insert(key, text.clone());
+    Ok(text)
+}
+
+/// Accepts `E0382`, `e0382` or `0382`, returning `E0382`.
+fn normalize_code(code: &str) -> Option<String> {
+    let code = code.trim();
+    let digits = code.strip_prefix(|c| c == 'E' || c == 'e').unwrap_or(code);
+    if digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit()) {
+        Some(format!("E{}", digits))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_code("E0382").as_deref(), Some("E0382"));
+        assert_eq!(normalize_code(" e0382").as_deref(), Some("E0382"));
+        assert_eq!(normalize_code("0382").as_deref(), Some("E0382"));
+        assert_eq!(normalize_code("E382"), None);
+        assert_eq!(normalize_code("unused_variables"), None);
+    }
+
+    #[test]
+    fn explain_known_and_unknown() {
+        let text = explain_error("stable", "E0382").expect("explain failed");
+        assert!(text.contains("moved"));
+        assert!(matches!(explain_error("stable", "E9999"), Err(Error::UnknownErrorCode(_))));
+    }
+}
@@ -0,0 +1,43 @@
+//! Formatting snippets with rustfmt.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::error::Error;
+
+/// Formats `code` with the given toolchain's rustfmt, using the same edition
+/// as the generated crate. Dependency comments (`//~ use ...`) are left
+/// where they are.
+pub fn format_code(toolchain: &str, code: &str) -> Result<String, Error> {
+    let mut child = Command::new("rustup")
+        .args(["run", toolchain, "rustfmt", "--edition", "2018", "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::ToolchainSelectFailed)?;
+
+    // rustfmt reads all of stdin before writing anything, so this can't block
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(code.as_bytes()).map_err(Error::CompileFailed)?;
+    drop(stdin);
+
+    let output = child.wait_with_output().map_err(Error::CompileFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to format code", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_snippet() {
+        let code = "//~ use itoa\nfn main(){println!(\"hi\");}\n";
+        let formatted = format_code("stable", code).expect("format failed");
+        assert_eq!(formatted, "//~ use itoa\nfn main() {\n    println!(\"hi\");\n}\n");
+        assert!(format_code("stable", "fn main( {").is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Listing output directories and removing those that are no longer needed.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::compile::{is_scaffold_manifest, OVERRIDE_LOCK};
+use crate::error::Error;
+
+/// The directories that tasks are running in, which are never removed.
+static ACTIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Marks a directory as in use by a task, until dropped.
+pub(crate) struct ActiveDir(PathBuf);
+
+impl ActiveDir {
+    pub(crate) fn enter(path: &Path) -> ActiveDir {
+        // the directory may not exist yet, so we can't canonicalize it
+        let path =
+            std::env::current_dir().map(|cwd| cwd.join(path)).unwrap_or_else(|_| path.into());
+        ACTIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner()).push(path.clone());
+        ActiveDir(path)
+    }
+}
+
+impl Drop for ActiveDir {
+    fn drop(&mut self) {
+        let mut active = ACTIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(idx) = active.iter().position(|p| *p == self.0) {
+            active.remove(idx);
+        }
+    }
+}
+
+/// Which output directories to remove.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GcPolicy {
+    /// Directories unused for longer than this are removed.
+    max_age_secs: Option<u64>,
+    /// After old directories are removed, the least recently used are
+    /// removed until the rest fit in this many bytes.
+    max_total_bytes: Option<u64>,
+    /// Directories that are never removed, such as those of pinned snippets.
+    pinned: Vec<PathBuf>,
+}
+
+/// A directory that tasks have been run in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputDir {
+    path: PathBuf,
+    /// The total size of the directory, including build artifacts.
+    size_bytes: u64,
+    /// When anything in the directory was last modified, in seconds since
+    /// the unix epoch.
+    last_used: u64,
+}
+
+/// What a call to `collect_garbage` did.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcReport {
+    removed: Vec<OutputDir>,
+    kept: Vec<OutputDir>,
+    freed_bytes: u64,
+    /// Directories whose rustup override was unset, including directories
+    /// under the root that had already been deleted.
+    overrides_removed: Vec<PathBuf>,
+    /// Directories that could not be removed, with the reason.
+    failed: Vec<(PathBuf, String)>,
+}
+
+/// Lists the output directories in `root`, most recently used first. An
+/// output directory is any immediate subdirectory containing the
+/// `Cargo.toml` we generate, so that other crates are never included;
+/// subdirectories created by `run_matrix` count towards their parent.
+pub fn list_output_dirs<P: AsRef<Path>>(root: P) -> Result<Vec<OutputDir>, Error> {
+    let root = root.as_ref();
+    let root = fs::canonicalize(root).map_err(|_| Error::ReadOutputFailed(root.to_owned()))?;
+    let mut dirs = fs::read_dir(&root)
+        .map_err(|_| Error::ReadOutputFailed(root.clone()))?
+        .filter_map(std::io::Result::ok)
+        .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .map(|e| e.path())
+        .filter(|p| {
+            let manifest = fs::read_to_string(p.join("Cargo.toml")).unwrap_or_default();
+            is_scaffold_manifest(&manifest)
+        })
+        .map(|path| {
+            let (size_bytes, modified) = dir_usage(&path);
+            let last_used = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            OutputDir { path, size_bytes, last_used }
+        })
+        .collect::<Vec<_>>();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.last_used));
+    Ok(dirs)
+}
+
+/// Removes the output directories in `root` that `policy` selects, along
+/// with their rustup overrides.
+///
+/// Directories that a task is running in are kept, as if pinned. New tasks
+/// wait to start until this returns.
+pub fn collect_garbage<P: AsRef<Path>>(root: P, policy: &GcPolicy) -> Result<GcReport, Error> {
+    let root = root.as_ref();
+    // held until we are done, so that no task starts in a directory we remove
+    let active_dirs = ACTIVE_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    let active = active_dirs.iter().map(|p| canonicalize_partial(p)).collect::<Vec<_>>();
+    let dirs = list_output_dirs(root)?;
+    let mut pinned = policy
+        .pinned
+        .iter()
+        .map(|p| fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+        .collect::<Vec<_>>();
+    pinned.extend(
+        dirs.iter()
+            .filter(|d| active.iter().any(|a| a.starts_with(&d.path)))
+            .map(|d| d.path.clone()),
+    );
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let selected = select_for_removal(&dirs, policy, &pinned, now);
+
+    let mut report = GcReport::default();
+    let mut to_unset = Vec::new();
+    for (idx, dir) in dirs.into_iter().enumerate() {
+        if !selected.contains(&idx) {
+            report.kept.push(dir);
+            continue;
+        }
+        to_unset.push(dir.path.clone());
+        match fs::remove_dir_all(&dir.path) {
+            Ok(()) => {
+                report.freed_bytes += dir.size_bytes;
+                report.removed.push(dir);
+            }
+            Err(e) => report.failed.push((dir.path.clone(), e.to_string())),
+        }
+    }
+
+    // overrides for directories that are already gone are cleaned up too;
+    // this is best effort, since the directories themselves are removed.
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_owned());
+    for (path, exists) in list_overrides().unwrap_or_default() {
+        let deleted = to_unset.contains(&path) && !path.exists();
+        if (deleted || (!exists && path.starts_with(&root))) && unset_override(&path).is_ok() {
+            report.overrides_removed.push(path);
+        }
+    }
+    Ok(report)
+}
+
+/// Canonicalizes the part of `path` that exists, e.g. for a task that has
+/// not created its directory yet.
+fn canonicalize_partial(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or_else(|_| Path::new(""));
+            return canonical.join(rest);
+        }
+    }
+    path.to_owned()
+}
+
+/// Returns the indices in `dirs` of the directories to remove: first those
+/// older than the age limit, then the least recently used until the rest
+/// fit in the size budget. Pinned directories are never selected.
+fn select_for_removal(
+    dirs: &[OutputDir],
+    policy: &GcPolicy,
+    pinned: &[PathBuf],
+    now: u64,
+) -> Vec<usize> {
+    let mut candidates =
+        (0..dirs.len()).filter(|&i| !pinned.contains(&dirs[i].path)).collect::<Vec<_>>();
+    // least recently used first
+    candidates.sort_by_key(|&i| dirs[i].last_used);
+
+    let mut selected = Vec::new();
+    if let Some(max_age) = policy.max_age_secs {
+        selected.extend(
+            candidates.iter().copied().filter(|&i| now.saturating_sub(dirs[i].last_used) > max_age),
+        );
+    }
+
+    if let Some(budget) = policy.max_total_bytes {
+        let mut total = dirs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !selected.contains(i))
+            .map(|(_, d)| d.size_bytes)
+            .sum::<u64>();
+        for &idx in candidates.iter() {
+            if total <= budget {
+                break;
+            }
+            if !selected.contains(&idx) {
+                total -= dirs[idx].size_bytes;
+                selected.push(idx);
+            }
+        }
+    }
+    selected
+}
+
+/// Returns the total size of the files under `path`, and the latest
+/// modification time of anything under it. Symlinks are not followed.
+fn dir_usage(path: &Path) -> (u64, SystemTime) {
+    let mut size = 0;
+    let mut latest = UNIX_EPOCH;
+    let mut stack = vec![path.to_owned()];
+    while let Some(dir) = stack.pop() {
+        if let Ok(modified) = fs::symlink_metadata(&dir).and_then(|m| m.modified()) {
+            latest = latest.max(modified);
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(std::io::Result::ok) {
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if meta.is_dir() {
+                stack.push(entry.path());
+            } else {
+                size += meta.len();
+                if let Ok(modified) = meta.modified() {
+                    latest = latest.max(modified);
+                }
+            }
+        }
+    }
+    (size, latest)
+}
+
+/// Returns the directories with a rustup override, and whether each exists.
+fn list_overrides() -> Result<Vec<(PathBuf, bool)>, Error> {
+    let output = Command::new("rustup")
+        .args(["override", "list"])
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to list overrides", &output));
+    }
+    Ok(parse_override_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses lines like `/path/to/dir (not a directory)\tstable-x86_64-...`.
+fn parse_override_list(text: &str) -> Vec<(PathBuf, bool)> {
+    text.lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(path, _)| {
+            let path = path.trim_end();
+            match path.strip_suffix("(not a directory)") {
+                Some(path) => (PathBuf::from(path.trim_end()), false),
+                None => (PathBuf::from(path), true),
+            }
+        })
+        .collect()
+}
+
+fn unset_override(path: &Path) -> Result<(), Error> {
+    let _guard = OVERRIDE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let output = Command::new("rustup")
+        .args(["override", "unset", "--path"])
+        .arg(path)
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::bad_output("Failed to unset toolchain", &output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str, size_bytes: u64, last_used: u64) -> OutputDir {
+        OutputDir { path: PathBuf::from("/out").join(name), size_bytes, last_used }
+    }
+
+    #[test]
+    fn select_by_age_and_budget() {
+        let dirs =
+            vec![dir("a", 100, 1000), dir("b", 100, 900), dir("c", 100, 500), dir("d", 50, 100)];
+        let pinned = vec![PathBuf::from("/out/c")];
+
+        let policy = GcPolicy { max_age_secs: Some(300), ..Default::default() };
+        assert_eq!(select_for_removal(&dirs, &policy, &pinned, 1000), vec![3]);
+
+        let policy = GcPolicy { max_total_bytes: Some(200), ..Default::default() };
+        assert_eq!(select_for_removal(&dirs, &policy, &pinned, 1000), vec![3, 1]);
+
+        let policy =
+            GcPolicy { max_age_secs: Some(300), max_total_bytes: Some(1000), ..Default::default() };
+        assert_eq!(select_for_removal(&dirs, &policy, &[], 1000), vec![3, 2]);
+    }
+
+    #[test]
+    fn list_and_collect() {
+        let root = tempdir::TempDir::new("gc").unwrap();
+        for name in &["one", "two"] {
+            let dir = root.path().join(name).join("src");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+            let manifest = "[package]\nname = \"playground\"\n";
+            fs::write(dir.parent().unwrap().join("Cargo.toml"), manifest).unwrap();
+        }
+        fs::create_dir(root.path().join("not-a-crate")).unwrap();
+        let other = root.path().join("users-crate");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("Cargo.toml"), "[package]\nname = \"mine\"\n").unwrap();
+
+        let dirs = list_output_dirs(root.path()).unwrap();
+        assert_eq!(dirs.len(), 2);
+        assert!(dirs.iter().all(|d| d.size_bytes == 43));
+
+        let policy = GcPolicy {
+            max_total_bytes: Some(0),
+            pinned: vec![root.path().join("two")],
+            ..Default::default()
+        };
+        let busy = ActiveDir::enter(&root.path().join("one").join("stable"));
+        let report = collect_garbage(root.path(), &policy).unwrap();
+        assert!(report.removed.is_empty());
+        drop(busy);
+
+        let report = collect_garbage(root.path(), &policy).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.freed_bytes, 43);
+        assert!(!root.path().join("one").exists());
+        assert!(root.path().join("two").exists());
+        assert!(other.join("Cargo.toml").exists());
+    }
+
+    #[test]
+    fn override_list() {
+        let text = "/tmp/a b (not a directory)\tstable-x86_64-unknown-linux-gnu\n\
+                    /tmp/c                  \tnightly-x86_64-unknown-linux-gnu\n\
+                    info: you may remove overrides for non-existent directories with\n";
+        assert_eq!(
+            parse_override_list(text),
+            vec![(PathBuf::from("/tmp/a b"), false), (PathBuf::from("/tmp/c"), true)]
+        );
+    }
+}
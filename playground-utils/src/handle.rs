@@ -0,0 +1,220 @@
+//! Running compile tasks in the background, with a handle for receiving
+//! their output and cancelling them.
+
+use std::path::Path;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::{Serialize, Serializer};
+
+use crate::compile::{compile_task, CompilerResult, Task};
+use crate::error::Error;
+use crate::output::{self, Stream};
+use crate::progress::BuildProgress;
+
+/// Something that happened while running a task in the background.
+#[derive(Debug)]
+pub enum TaskEvent {
+    /// Text from cargo's stderr, as passed to `do_compile_task`'s callback.
+    Stderr(String),
+    /// A line of cargo's stdout, as passed to `do_compile_task_with_output`.
+    Stdout(String),
+    Progress(BuildProgress),
+    /// The task finished; this is always the last event. The result is
+    /// boxed, as it is much larger than the other events.
+    Done(Box<Result<CompilerResult, Error>>),
+}
+
+/// A task running on a background thread, created by `spawn_compile_task`
+/// or `CompileService::start`.
+///
+/// Events can be polled with `try_next`, or waited for by iterating over
+/// the handle; iteration ends after the `Done` event.
+pub struct TaskHandle {
+    events: Receiver<TaskEvent>,
+    cancel: CancelToken,
+}
+
+/// Lets a task be cancelled from another thread, killing cargo if it is
+/// running.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancelToken {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    child: Mutex<Option<Arc<Mutex<Child>>>>,
+}
+
+/// Sends a task's output and result to its `TaskHandle`.
+#[derive(Clone)]
+pub(crate) struct EventSender(Sender<TaskEvent>);
+
+/// Starts `task` on a new thread and returns immediately.
+///
+/// As with `do_compile_task`, only one task should run in `outdir` at a
+/// time; `CompileService::start` queues them instead.
+pub fn spawn_compile_task<P: AsRef<Path>>(outdir: P, task: Task) -> TaskHandle {
+    let outdir = outdir.as_ref().to_owned();
+    let (handle, events, cancel) = TaskHandle::new();
+
+    thread::spawn(move || {
+        let result = compile_task(
+            &outdir,
+            task,
+            |stream, text| events.output(stream, text),
+            |event| events.progress(event),
+            Some(&cancel),
+        );
+        events.done(result);
+    });
+
+    handle
+}
+
+impl TaskHandle {
+    /// Creates a handle, along with the sender for its events and the token
+    /// that cancels it.
+    pub(crate) fn new() -> (TaskHandle, EventSender, CancelToken) {
+        let (sender, events) = mpsc::channel();
+        let cancel = CancelToken::default();
+        (TaskHandle { events, cancel: cancel.clone() }, EventSender(sender), cancel)
+    }
+
+    /// Returns the next event if one is available, without blocking.
+    pub fn try_next(&mut self) -> Option<TaskEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Stops the task. Cargo is killed if it is running, and the task
+    /// finishes with `Error::Cancelled`. A program that has already started
+    /// running is allowed to finish.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Blocks until the task finishes, discarding any other events.
+    pub fn wait(self) -> Result<CompilerResult, Error> {
+        for event in self {
+            if let TaskEvent::Done(result) = event {
+                return *result;
+            }
+        }
+        Err(Error::TaskAborted)
+    }
+}
+
+impl Iterator for TaskHandle {
+    type Item = TaskEvent;
+
+    /// Blocks until the next event; returns `None` after the task finishes.
+    fn next(&mut self) -> Option<TaskEvent> {
+        self.events.recv().ok()
+    }
+}
+
+impl EventSender {
+    pub(crate) fn output(&self, stream: Stream, text: &str) {
+        let event = match stream {
+            Stream::Stdout => TaskEvent::Stdout(text.to_owned()),
+            Stream::Stderr => TaskEvent::Stderr(text.to_owned()),
+        };
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn progress(&self, progress: BuildProgress) {
+        let _ = self.0.send(TaskEvent::Progress(progress));
+    }
+
+    pub(crate) fn done(&self, result: Result<CompilerResult, Error>) {
+        let _ = self.0.send(TaskEvent::Done(Box::new(result)));
+    }
+}
+
+impl CancelToken {
+    pub(crate) fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        if let Some(child) = self.inner.child.lock().unwrap().take() {
+            output::kill_child(&child);
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Makes `child` the process that is killed on cancellation.
+    pub(crate) fn register(&self, child: &Arc<Mutex<Child>>) {
+        *self.inner.child.lock().unwrap() = Some(child.clone());
+        // we may have been cancelled just before the child was registered
+        if self.is_cancelled() {
+            output::kill_child(child);
+        }
+    }
+}
+
+impl Serialize for TaskEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        #[serde(tag = "event", rename_all = "snake_case")]
+        enum Json<'a> {
+            Stderr { text: &'a str },
+            Stdout { text: &'a str },
+            Progress { progress: &'a BuildProgress },
+            Finished { result: &'a CompilerResult },
+            Failed { error: &'a Error },
+        }
+
+        let json = match self {
+            TaskEvent::Stderr(text) => Json::Stderr { text },
+            TaskEvent::Stdout(text) => Json::Stdout { text },
+            TaskEvent::Progress(progress) => Json::Progress { progress },
+            TaskEvent::Done(done) => match done.as_ref() {
+                Ok(result) => Json::Finished { result },
+                Err(error) => Json::Failed { error },
+            },
+        };
+        json.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(code: &str) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "toolchain": "stable",
+            "code": code,
+            "task_type": "check",
+            "backtrace": false,
+            "release": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn events_then_result() {
+        let dir = tempdir::TempDir::new("handle").unwrap();
+        let handle = spawn_compile_task(dir.path(), task("fn main() {}"));
+        let events = handle.collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e, TaskEvent::Stderr(_))));
+        match events.last() {
+            Some(TaskEvent::Done(done)) => assert!(matches!(**done, Ok(ref r) if r.success())),
+            other => panic!("unexpected last event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cancel_before_start() {
+        let dir = tempdir::TempDir::new("handle").unwrap();
+        let handle = spawn_compile_task(dir.path(), task("fn main() {}"));
+        handle.cancel();
+        assert!(matches!(handle.wait(), Err(Error::Cancelled)));
+    }
+}
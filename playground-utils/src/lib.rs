@@ -1,14 +1,64 @@
 #[macro_use]
 extern crate serde_derive;
 
+mod ansi;
+mod bisect;
 mod compile;
+mod deps;
+mod doc;
 mod error;
+mod explain;
+mod format;
+mod gc;
+mod handle;
+mod lint;
+mod lockfile;
+mod matrix;
+mod output;
+mod panic;
+mod progress;
+mod registry;
+mod run;
 mod rustup;
+mod sandbox;
+mod sanitizer;
+mod service;
+mod size;
+mod suggest;
+mod templates;
+mod timings;
 mod toolchain;
 
 #[cfg(not(target_os = "macos"))]
 compile_error!("this library is currently macOS only.");
 
-pub use compile::{do_compile_task, Task};
-pub use error::Error;
+pub use ansi::{parse_ansi, RgbaColor, Style, StyledSpan, StyledText};
+pub use bisect::{bisect_nightlies, BisectPredicate, BisectProgress, BisectResult, BisectStep};
+pub use compile::{
+    do_compile_task, do_compile_task_with_output, do_compile_task_with_progress, CompilerResult,
+    Task,
+};
+pub use deps::{DependencyGraph, PackageId, ResolvedPackage};
+pub use doc::DocIndex;
+pub use error::{take_last_error_details, Error};
+pub use explain::explain_error;
+pub use format::format_code;
+pub use gc::{collect_garbage, list_output_dirs, GcPolicy, GcReport, OutputDir};
+pub use handle::{spawn_compile_task, TaskEvent, TaskHandle};
+pub use lint::LintConfig;
+pub use matrix::{run_matrix, MatrixEntry, MatrixReport};
+pub use output::{OutputLimit, Stream};
+pub use panic::{BacktraceFrame, PanicReport};
+pub use progress::BuildProgress;
+pub use registry::{
+    complete_crate_names, crate_versions, record_crate_usage, CrateCompletion, CrateVersion,
+};
+pub use run::{run_program, RunResult};
+pub use sandbox::{run_sandboxed, Isolation, SandboxConfig, SandboxLimit};
+pub use sanitizer::{Sanitizer, SanitizerReport, StackFrame};
+pub use service::{CompileService, PendingTask};
+pub use size::{analyze_binary_size, compare_binary_sizes, SizeComparison, SizeReport};
+pub use suggest::{apply_suggestions, list_suggestions, AppliedSuggestions, Edit, Suggestion};
+pub use templates::{instantiate_template, list_templates, save_template, Placeholder, Template};
+pub use timings::{PassTiming, TimingsReport, UnitTiming};
 pub use toolchain::{list_toolchains, ToolchainInfo};
@@ -0,0 +1,314 @@
+//! Per-task lint levels, applied through the manifest or `RUSTFLAGS`.
+
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use semver::Version;
+
+use crate::error::Error;
+use crate::toolchain;
+
+lazy_static! {
+    static ref TOOLCHAIN_LINTS: Mutex<HashMap<String, ToolchainLints>> = Mutex::new(HashMap::new());
+}
+
+/// Lint levels for a task.
+#[derive(Debug, Clone, Default, PartialEq, Hash, Deserialize)]
+#[serde(default)]
+pub struct LintConfig {
+    /// Turns all warnings into errors.
+    deny_warnings: bool,
+    /// Enables the `clippy::pedantic` group. Clippy lints are only checked
+    /// by `check` tasks, which run clippy when any are configured.
+    clippy_pedantic: bool,
+    /// Lint names, e.g. `dead_code` or `clippy::unwrap_used`.
+    allow: Vec<String>,
+    warn: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// What a toolchain knows about lints.
+#[derive(Debug, Clone)]
+struct ToolchainLints {
+    /// Lint and group names, with dashes, e.g. `clippy::needless-borrow`.
+    known: HashSet<String>,
+    has_clippy: bool,
+    /// Cargo supports the `[lints]` manifest table from 1.74.
+    supports_manifest_lints: bool,
+}
+
+/// A `LintConfig` checked against a toolchain.
+#[derive(Debug, Default)]
+pub(crate) struct ResolvedLints {
+    /// Lints in the order they should be applied, groups first.
+    lints: Vec<(String, Level, i8)>,
+    use_manifest: bool,
+    /// Messages about lints that were ignored.
+    warnings: Vec<String>,
+}
+
+impl LintConfig {
+    pub(crate) fn is_empty(&self) -> bool {
+        self == &LintConfig::default()
+    }
+
+    /// `true` if any clippy lints are configured.
+    pub(crate) fn uses_clippy(&self) -> bool {
+        self.clippy_pedantic
+            || self.allow.iter().chain(&self.warn).chain(&self.deny).any(|l| is_clippy_lint(l))
+    }
+
+    /// Checks this configuration against the lints available on `toolchain`.
+    /// Lints that the toolchain does not know are dropped with a warning,
+    /// since rustc would otherwise fail under `deny_warnings`.
+    pub(crate) fn resolve(&self, toolchain: &str) -> Result<ResolvedLints, Error> {
+        if self.is_empty() {
+            return Ok(ResolvedLints::default());
+        }
+
+        let info = toolchain_lints(toolchain)?;
+        let mut resolved =
+            ResolvedLints { use_manifest: info.supports_manifest_lints, ..Default::default() };
+
+        if self.deny_warnings {
+            resolved.lints.push(("warnings".into(), Level::Deny, -2));
+        }
+        if self.clippy_pedantic {
+            if info.has_clippy {
+                resolved.lints.push(("clippy::pedantic".into(), Level::Warn, -1));
+            } else {
+                resolved.warnings.push(no_clippy_warning(toolchain, "clippy::pedantic"));
+            }
+        }
+
+        let levels =
+            [(&self.allow, Level::Allow), (&self.warn, Level::Warn), (&self.deny, Level::Deny)];
+        for (names, level) in levels.iter() {
+            for name in names.iter() {
+                let name = name.trim();
+                if is_clippy_lint(name) && !info.has_clippy {
+                    resolved.warnings.push(no_clippy_warning(toolchain, name));
+                } else if !info.known.contains(&name.replace('_', "-")) {
+                    resolved.warnings.push(format!(
+                        "warning: unknown lint `{}` on toolchain '{}'; it will be ignored\n",
+                        name, toolchain
+                    ));
+                } else {
+                    resolved.lints.push((name.to_owned(), *level, 0));
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+impl ResolvedLints {
+    pub(crate) fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Flags to be added to `RUSTFLAGS`, if the manifest cannot be used.
+    pub(crate) fn rustflags(&self) -> Vec<String> {
+        if self.use_manifest {
+            return Vec::new();
+        }
+        self.lints.iter().map(|(name, level, _)| format!("{}{}", level.flag(), name)).collect()
+    }
+
+    /// The `[lints]` tables to append to the manifest, if supported.
+    pub(crate) fn manifest_section(&self) -> String {
+        if !self.use_manifest || self.lints.is_empty() {
+            return String::new();
+        }
+
+        let mut tools: Vec<(&str, Vec<String>)> = Vec::new();
+        for (name, level, priority) in self.lints.iter() {
+            let (tool, name) = match name.split_once("::") {
+                Some((tool, name)) => (tool, name),
+                None => ("rust", name.as_str()),
+            };
+            let entry = if *priority == 0 {
+                format!("{} = \"{}\"", name, level.as_str())
+            } else {
+                format!("{} = {{ level = \"{}\", priority = {} }}", name, level.as_str(), priority)
+            };
+            match tools.iter_mut().find(|(t, _)| *t == tool) {
+                Some((_, entries)) => entries.push(entry),
+                None => tools.push((tool, vec![entry])),
+            }
+        }
+
+        let mut section = String::new();
+        for (tool, entries) in tools {
+            section.push_str(&format!("\n[lints.{}]\n", tool));
+            for entry in entries {
+                section.push_str(&entry);
+                section.push('\n');
+            }
+        }
+        section
+    }
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Allow => "allow",
+            Level::Warn => "warn",
+            Level::Deny => "deny",
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            Level::Allow => "-A",
+            Level::Warn => "-W",
+            Level::Deny => "-D",
+        }
+    }
+}
+
+fn is_clippy_lint(name: &str) -> bool {
+    name.trim().starts_with("clippy::")
+}
+
+fn no_clippy_warning(toolchain: &str, lint: &str) -> String {
+    format!(
+        "warning: clippy is not installed for toolchain '{}'; `{}` will be ignored\n",
+        toolchain, lint
+    )
+}
+
+fn toolchain_lints(toolchain: &str) -> Result<ToolchainLints, Error> {
+    if let Some(info) = TOOLCHAIN_LINTS.lock().unwrap().get(toolchain) {
+        return Ok(info.clone());
+    }
+
+    // clippy-driver lists both rustc's lints and its own
+    let clippy_help = lint_help(toolchain, &["clippy-driver", "rustc", "-W", "help"]);
+    let (help, has_clippy) = match clippy_help {
+        Ok(help) => (help, true),
+        Err(_) => (lint_help(toolchain, &["rustc", "-W", "help"])?, false),
+    };
+    let supports_manifest_lints = supports_manifest_lints(&toolchain::cargo_version(toolchain)?);
+
+    let info =
+        ToolchainLints { known: parse_lint_names(&help), has_clippy, supports_manifest_lints };
+    TOOLCHAIN_LINTS.lock().unwrap().insert(toolchain.to_owned(), info.clone());
+    Ok(info)
+}
+
+/// `[lints]` tables are read by cargo 1.74 and later. The pre-release is
+/// ignored, as semver orders e.g. `1.74.0-nightly` before `1.74.0`.
+fn supports_manifest_lints(cargo: &Version) -> bool {
+    (cargo.major, cargo.minor, cargo.patch) >= (1, 74, 0)
+}
+
+fn lint_help(toolchain: &str, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new("rustup")
+        .args(["run", toolchain])
+        .args(args)
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to list lints", &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Extracts lint and group names from the tables printed by `rustc -W help`.
+fn parse_lint_names(help: &str) -> HashSet<String> {
+    help.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "name")
+        .filter(|name| {
+            name.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == ':')
+                && name.chars().any(|c| c.is_ascii_lowercase())
+        })
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(use_manifest: bool) -> ResolvedLints {
+        ResolvedLints {
+            lints: vec![
+                ("warnings".into(), Level::Deny, -2),
+                ("clippy::pedantic".into(), Level::Warn, -1),
+                ("dead_code".into(), Level::Allow, 0),
+                ("clippy::unwrap_used".into(), Level::Deny, 0),
+            ],
+            use_manifest,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn manifest_section() {
+        assert_eq!(
+            resolved(true).manifest_section(),
+            "\n[lints.rust]\n\
+             warnings = { level = \"deny\", priority = -2 }\n\
+             dead_code = \"allow\"\n\
+             \n[lints.clippy]\n\
+             pedantic = { level = \"warn\", priority = -1 }\n\
+             unwrap_used = \"deny\"\n"
+        );
+        assert!(resolved(true).rustflags().is_empty());
+    }
+
+    #[test]
+    fn rustflags() {
+        assert_eq!(
+            resolved(false).rustflags(),
+            vec!["-Dwarnings", "-Wclippy::pedantic", "-Adead_code", "-Dclippy::unwrap_used"]
+        );
+        assert_eq!(resolved(false).manifest_section(), "");
+    }
+
+    #[test]
+    fn manifest_lints_version() {
+        let supports = |v| supports_manifest_lints(&Version::parse(v).unwrap());
+        assert!(supports("1.74.0-nightly"));
+        assert!(supports("1.74.0-beta.3"));
+        assert!(supports("1.80.1"));
+        assert!(!supports("1.73.0"));
+    }
+
+    #[test]
+    fn lint_names() {
+        let help = "
+Lint checks provided by rustc:
+
+                    name  default  meaning
+                    ----  -------  -------
+               dead-code  warn     detect unused, unexported items
+  clippy::needless-borrow  warn     checks for needless borrows
+
+Lint groups provided by rustc:
+
+                    name  sub-lints
+                    ----  ---------
+                warnings  all lints that are set to issue warnings
+";
+        let names = parse_lint_names(help);
+        assert!(names.contains("dead-code"));
+        assert!(names.contains("clippy::needless-borrow"));
+        assert!(names.contains("warnings"));
+        assert!(!names.contains("name"));
+        assert!(!names.contains("----"));
+    }
+}
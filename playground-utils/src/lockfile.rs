@@ -0,0 +1,169 @@
+//! Minimal reading of the `Cargo.lock` generated for a snippet.
+
+use std::fs;
+use std::path::Path;
+
+/// A package entry in a lockfile.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LockedPackage {
+    name: String,
+    version: String,
+    /// Where the package came from; `None` for the snippet itself.
+    source: Option<String>,
+    /// Entries of the form `name`, or `name version` when there is more
+    /// than one version of `name` in the lockfile.
+    dependencies: Vec<String>,
+}
+
+impl LockedPackage {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub(crate) fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub(crate) fn dependencies(&self) -> &[String] {
+        &self.dependencies
+    }
+
+    /// Reads the lockfile in `dir`, returning an empty list if it does not exist.
+    pub(crate) fn read_lockfile(dir: &Path) -> Vec<LockedPackage> {
+        fs::read_to_string(dir.join("Cargo.lock")).map(|s| parse_lockfile(&s)).unwrap_or_default()
+    }
+}
+
+/// We only need a few string fields, so we avoid a full toml parser.
+fn parse_lockfile(text: &str) -> Vec<LockedPackage> {
+    let mut packages = Vec::new();
+    let mut current: Option<PartialPackage> = None;
+    let mut in_dependencies = false;
+
+    for line in text.lines().map(str::trim) {
+        if in_dependencies {
+            match line {
+                "]" => in_dependencies = false,
+                dep => {
+                    if let Some(current) = current.as_mut() {
+                        current
+                            .dependencies
+                            .push(dep.trim_end_matches(',').trim_matches('"').into());
+                    }
+                }
+            }
+            continue;
+        }
+
+        if line.starts_with('[') {
+            packages.extend(current.take().and_then(PartialPackage::finish));
+            if line == "[[package]]" {
+                current = Some(PartialPackage::default());
+            }
+            continue;
+        }
+
+        if let Some(current) = current.as_mut() {
+            let mut split = line.splitn(2, '=');
+            let key = split.next().unwrap_or("").trim();
+            let value = split.next().map(|v| v.trim().trim_matches('"').to_owned());
+            match key {
+                "name" => current.name = value,
+                "version" => current.version = value,
+                "source" => current.source = value,
+                "dependencies" if value.as_deref() == Some("[") => in_dependencies = true,
+                _ => (),
+            }
+        }
+    }
+
+    packages.extend(current.and_then(PartialPackage::finish));
+    packages
+}
+
+#[derive(Default)]
+struct PartialPackage {
+    name: Option<String>,
+    version: Option<String>,
+    source: Option<String>,
+    dependencies: Vec<String>,
+}
+
+impl PartialPackage {
+    fn finish(self) -> Option<LockedPackage> {
+        let PartialPackage { name, version, source, dependencies } = self;
+        // older lockfiles list the source too: `name version (source)`
+        let dependencies = dependencies
+            .into_iter()
+            .map(|dep| match dep.find(" (") {
+                Some(idx) => dep[..idx].to_owned(),
+                None => dep,
+            })
+            .collect();
+        Some(LockedPackage { name: name?, version: version?, source, dependencies })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_simple_lockfile() {
+        let lockfile = r#"
+# This file is automatically @generated by Cargo.
+[[package]]
+name = "itoa"
+version = "1.0.1"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "abcd"
+
+[[package]]
+name = "playground"
+version = "0.0.0"
+dependencies = [
+ "itoa",
+]
+
+[metadata]
+"checksum foo" = "bar"
+"#;
+        let packages = parse_lockfile(lockfile);
+        assert_eq!(
+            packages,
+            vec![
+                LockedPackage {
+                    name: "itoa".into(),
+                    version: "1.0.1".into(),
+                    source: Some("registry+https://github.com/rust-lang/crates.io-index".into()),
+                    dependencies: vec![],
+                },
+                LockedPackage {
+                    name: "playground".into(),
+                    version: "0.0.0".into(),
+                    source: None,
+                    dependencies: vec!["itoa".into()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_old_lockfile_dependencies() {
+        let lockfile = r#"
+[[package]]
+name = "playground"
+version = "0.0.0"
+dependencies = [
+ "rand 0.6.5 (registry+https://github.com/rust-lang/crates.io-index)",
+ "rand 0.7.3 (registry+https://github.com/rust-lang/crates.io-index)",
+]
+"#;
+        let packages = parse_lockfile(lockfile);
+        assert_eq!(packages[0].dependencies(), &["rand 0.6.5", "rand 0.7.3"]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Running one task on several toolchains, to compare their behaviour.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+use crate::compile::Task;
+use crate::error::Error;
+use crate::gc::ActiveDir;
+use crate::service::CompileService;
+
+/// The result of running a task on one toolchain.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MatrixEntry {
+    toolchain: String,
+    /// `true` if the build succeeded.
+    compiled: bool,
+    /// The program's output for `run` tasks, or cargo's for `test` tasks.
+    stdout: Option<String>,
+    /// The program's exit code, for `run` tasks. `None` if it was killed
+    /// by a signal.
+    exit_code: Option<i32>,
+    /// Whether the program exited successfully, if it was run. A program
+    /// killed by a signal did not.
+    run_succeeded: Option<bool>,
+    /// The time taken to build, including any wait for other tasks in the
+    /// same directory.
+    build_secs: f64,
+    run_secs: Option<f64>,
+    /// Set if the task could not be run at all, e.g. if the toolchain is
+    /// not installed.
+    error: Option<String>,
+    /// `true` if the outcome differs from that of the first toolchain.
+    differs: bool,
+}
+
+/// The results of running a task on each of a set of toolchains.
+#[derive(Debug, Clone, Serialize)]
+pub struct MatrixReport {
+    /// One entry per toolchain, in the order they were requested.
+    entries: Vec<MatrixEntry>,
+    /// `true` if any toolchain's outcome differs from the first.
+    has_differences: bool,
+}
+
+/// Builds and, for `run` tasks, runs `task` with each of `toolchains`.
+///
+/// Each toolchain gets its own subdirectory of `outdir`, so that the builds
+/// can run in parallel; each is queued with `service`, like any other task
+/// in that directory. The task's own toolchain is ignored, as are repeats
+/// of a toolchain.
+pub fn run_matrix<P: AsRef<Path>>(
+    service: &CompileService,
+    outdir: P,
+    task: &Task,
+    toolchains: &[String],
+) -> Result<MatrixReport, Error> {
+    let outdir = outdir.as_ref();
+    // so that no subdirectory is collected before its task is queued
+    let _active = ActiveDir::enter(outdir);
+    let mut unique = Vec::with_capacity(toolchains.len());
+    for toolchain in toolchains {
+        if !unique.contains(&toolchain) {
+            unique.push(toolchain);
+        }
+    }
+    let handles = unique
+        .into_iter()
+        .map(|toolchain| {
+            let task = task.with_toolchain(toolchain);
+            let dir = outdir.join(dir_name_for_toolchain(toolchain));
+            let toolchain = toolchain.clone();
+            let service = service.clone();
+            thread::spawn(move || run_entry(&service, &dir, task, toolchain))
+        })
+        .collect::<Vec<_>>();
+
+    let mut entries = Vec::with_capacity(handles.len());
+    for handle in handles {
+        entries.push(handle.join().map_err(|_| Error::TaskAborted)?);
+    }
+    let has_differences = mark_differences(&mut entries);
+    Ok(MatrixReport { entries, has_differences })
+}
+
+/// Builds `task` in `dir` and, for `run` tasks, runs the program, as one
+/// job queued with `service`.
+pub(crate) fn run_entry(
+    service: &CompileService,
+    dir: &Path,
+    task: Task,
+    toolchain: String,
+) -> MatrixEntry {
+    let mut entry = MatrixEntry { toolchain, ..Default::default() };
+    let is_run = task.is_run();
+
+    let start = Instant::now();
+    let result = match service.submit_and_run(dir, task, |_| {}, |_| {}).wait() {
+        Ok(result) => result,
+        Err(e) => {
+            entry.error = Some(e.to_string());
+            return entry;
+        }
+    };
+    let elapsed = start.elapsed().as_secs_f64();
+    entry.compiled = result.success();
+
+    if !is_run {
+        entry.build_secs = elapsed;
+        entry.stdout = Some(result.stdout().to_owned()).filter(|s| !s.is_empty());
+        return entry;
+    }
+
+    match result.run() {
+        Some(run) => {
+            entry.build_secs = (elapsed - run.elapsed_secs).max(0.0);
+            entry.run_secs = Some(run.elapsed_secs);
+            entry.exit_code = run.exit_code;
+            entry.run_succeeded = Some(run.success);
+            entry.stdout = Some(run.stdout.clone());
+        }
+        None => entry.build_secs = elapsed,
+    }
+    entry
+}
+
+/// Compares each entry with the first, returning `true` if any differ.
+fn mark_differences(entries: &mut [MatrixEntry]) -> bool {
+    let reference = match entries.first() {
+        Some(first) => first.outcome(),
+        None => return false,
+    };
+    let mut any = false;
+    for entry in entries.iter_mut() {
+        entry.differs = entry.outcome() != reference;
+        any |= entry.differs;
+    }
+    any
+}
+
+impl MatrixEntry {
+    pub(crate) fn compiled(&self) -> bool {
+        self.compiled
+    }
+
+    pub(crate) fn stdout(&self) -> Option<&str> {
+        self.stdout.as_deref()
+    }
+
+    pub(crate) fn run_succeeded(&self) -> Option<bool> {
+        self.run_succeeded
+    }
+
+    pub(crate) fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// The parts of an entry that we compare; timings are expected to vary.
+    fn outcome(&self) -> (bool, Option<String>, Option<i32>, Option<bool>, bool) {
+        let MatrixEntry { compiled, exit_code, run_succeeded, .. } = *self;
+        (compiled, self.stdout.clone(), exit_code, run_succeeded, self.error.is_some())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_run(compiled: bool, run_succeeded: Option<bool>) -> MatrixEntry {
+        MatrixEntry { compiled, run_succeeded, ..Default::default() }
+    }
+}
+
+/// Toolchain names are used as directory names; `1.70.0` and
+/// `nightly-2023-01-01` are fine, but we avoid anything surprising.
+///
+/// If a name has to be escaped, or is only dots (and so would name `outdir`
+/// or its parent), a hash of it is appended, so that different toolchains
+/// never share a directory.
+fn dir_name_for_toolchain(toolchain: &str) -> PathBuf {
+    let mut name = toolchain
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect::<String>();
+    if name != toolchain || name.chars().all(|c| c == '.') {
+        let mut hasher = DefaultHasher::new();
+        toolchain.hash(&mut hasher);
+        name.push_str(&format!("-{:08x}", hasher.finish() as u32));
+    }
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(toolchain: &str, compiled: bool, stdout: &str) -> MatrixEntry {
+        MatrixEntry {
+            toolchain: toolchain.into(),
+            compiled,
+            stdout: Some(stdout.into()),
+            exit_code: Some(0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn differences() {
+        let mut entries = vec![
+            entry("stable", true, "hello\n"),
+            entry("1.60.0", false, ""),
+            entry("nightly", true, "hello\n"),
+        ];
+        assert!(mark_differences(&mut entries));
+        assert_eq!(entries.iter().map(|e| e.differs).collect::<Vec<_>>(), vec![false, true, false]);
+
+        let mut same = vec![entry("stable", true, "a"), entry("beta", true, "a")];
+        assert!(!mark_differences(&mut same));
+    }
+
+    #[test]
+    fn toolchain_dirs() {
+        assert_eq!(
+            dir_name_for_toolchain("nightly-2019-01-26"),
+            PathBuf::from("nightly-2019-01-26")
+        );
+        assert!(dir_name_for_toolchain("../stable").to_str().unwrap().starts_with(".._stable-"));
+        assert_ne!(dir_name_for_toolchain("../stable"), dir_name_for_toolchain(".._stable"));
+        for name in &["", ".", ".."] {
+            let dir = dir_name_for_toolchain(name);
+            assert_eq!(dir.components().count(), 1);
+            assert!(matches!(dir.components().next(), Some(std::path::Component::Normal(_))));
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Limits on the amount of output we pass on from cargo and user programs.
+
+use std::io::{BufRead, BufReader, Read};
+use std::process::Child;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Appended to output that was cut off because it exceeded a limit.
+pub(crate) const TRUNCATION_MARKER: &str = "\n[output truncated]\n";
+
+/// The shortest pieces that long lines are split into; see
+/// `OutputLimit::line_bytes`.
+const MIN_LINE_BYTES: usize = 8192;
+
+/// Which of a process's output streams some text came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Caps on how much output is sent to the stderr callback and collected
+/// into results.
+#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputLimit {
+    /// The maximum number of bytes passed to the output callback, for each
+    /// of stdout and stderr.
+    streamed_bytes: usize,
+    /// The maximum number of bytes collected for each of stdout and stderr.
+    collected_bytes: usize,
+    /// If `true`, the process is killed as soon as a limit is exceeded.
+    /// For builds, this includes any programs cargo started, such as tests.
+    kill_on_exceed: bool,
+}
+
+impl Default for OutputLimit {
+    fn default() -> Self {
+        OutputLimit { streamed_bytes: 1 << 20, collected_bytes: 1 << 20, kill_on_exceed: false }
+    }
+}
+
+impl OutputLimit {
+    pub(crate) fn streamed_bytes(&self) -> usize {
+        self.streamed_bytes
+    }
+
+    pub(crate) fn collected_bytes(&self) -> usize {
+        self.collected_bytes
+    }
+
+    pub(crate) fn kill_on_exceed(&self) -> bool {
+        self.kill_on_exceed
+    }
+
+    /// The most of a line that is read before it is passed on, so that
+    /// output without newlines cannot use unbounded memory. Longer lines are
+    /// passed on in pieces; cargo's json messages are only split if they are
+    /// longer than both limits.
+    pub(crate) fn line_bytes(&self) -> usize {
+        self.streamed_bytes.max(self.collected_bytes).max(MIN_LINE_BYTES)
+    }
+}
+
+/// Tracks how much of a stream has been passed on, so that we can stop at
+/// a limit.
+#[derive(Debug)]
+pub(crate) struct OutputCap {
+    remaining: usize,
+    truncated: bool,
+}
+
+impl OutputCap {
+    pub(crate) fn new(limit: usize) -> Self {
+        OutputCap { remaining: limit, truncated: false }
+    }
+
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Returns the part of `text` that fits within the limit. The first time
+    /// the limit is exceeded the marker is appended; after that, this
+    /// returns `None`.
+    pub(crate) fn take(&mut self, text: &str) -> Option<String> {
+        if self.truncated {
+            return None;
+        }
+        if text.len() <= self.remaining {
+            self.remaining -= text.len();
+            return Some(text.to_owned());
+        }
+
+        let mut end = self.remaining;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.remaining = 0;
+        self.truncated = true;
+        Some(format!("{}{}", &text[..end], TRUNCATION_MARKER))
+    }
+}
+
+/// Reads `reader` to the end, keeping at most `limit` bytes. `on_exceed` is
+/// called once if the limit is exceeded; reading continues afterwards so
+/// that the writer is not blocked, but the extra output is discarded.
+///
+/// Returns the collected text and whether it was truncated.
+pub(crate) fn read_capped<R, F>(mut reader: R, limit: usize, mut on_exceed: F) -> (String, bool)
+where
+    R: Read,
+    F: FnMut(),
+{
+    let mut collected = Vec::new();
+    let mut buf = [0; 8192];
+    let mut truncated = false;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if truncated {
+            continue;
+        }
+        let available = limit - collected.len();
+        if n > available {
+            collected.extend_from_slice(&buf[..available]);
+            truncated = true;
+            on_exceed();
+        } else {
+            collected.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    let mut text = String::from_utf8_lossy(&collected).into_owned();
+    if truncated {
+        // we may have split a multibyte character
+        if text.ends_with(char::REPLACEMENT_CHARACTER) {
+            text.pop();
+        }
+        text.push_str(TRUNCATION_MARKER);
+    }
+    (text, truncated)
+}
+
+/// Collects `reader` on a background thread with `read_capped`, killing
+/// `child` when the limit is exceeded if the limit requires it.
+pub(crate) fn collect_capped<R>(
+    reader: R,
+    limit: OutputLimit,
+    child: &Arc<Mutex<Child>>,
+) -> JoinHandle<(String, bool)>
+where
+    R: Read + Send + 'static,
+{
+    let child = child.clone();
+    thread::spawn(move || {
+        read_capped(reader, limit.collected_bytes, || {
+            if limit.kill_on_exceed {
+                kill_child(&child);
+            }
+        })
+    })
+}
+
+/// Reads lines from both `stdout` and `stderr` on background threads,
+/// returning them in the order they were read. The receiver is closed once
+/// both streams have ended. Lines longer than `max_line` bytes are returned
+/// in pieces, split between characters.
+///
+/// Lines from different streams are only ordered as well as the reader
+/// threads are scheduled; lines from the same stream are always in order.
+pub(crate) fn merge_lines<O, E>(stdout: O, stderr: E, max_line: usize) -> Receiver<(Stream, String)>
+where
+    O: Read + Send + 'static,
+    E: Read + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    read_lines(stdout, Stream::Stdout, max_line, sender.clone());
+    read_lines(stderr, Stream::Stderr, max_line, sender);
+    receiver
+}
+
+fn read_lines<R>(reader: R, stream: Stream, max_line: usize, sender: Sender<(Stream, String)>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            let room = max_line.saturating_sub(buf.len()).max(1) as u64;
+            // a read error ends the stream, as it would at the end of the pipe
+            let ended =
+                matches!(reader.by_ref().take(room).read_until(b'\n', &mut buf), Ok(0) | Err(_));
+            if buf.is_empty() {
+                break;
+            }
+            // the rest of a character split by the limit is kept for the next piece
+            let end = if ended || buf.ends_with(b"\n") { buf.len() } else { char_boundary(&buf) };
+            let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+            buf.drain(..end);
+            if sender.send((stream, line)).is_err() || ended {
+                break;
+            }
+        }
+    });
+}
+
+/// The length of `bytes` without any incomplete UTF-8 character at its end.
+fn char_boundary(bytes: &[u8]) -> usize {
+    // a character is at most four bytes, so only the last three can start
+    // an incomplete one
+    for start in (bytes.len().saturating_sub(3)..bytes.len()).rev() {
+        let byte = bytes[start];
+        if byte & 0xc0 == 0x80 {
+            continue;
+        }
+        let width = match byte {
+            0xf0..=0xff => 4,
+            0xe0..=0xef => 3,
+            0xc0..=0xdf => 2,
+            _ => 1,
+        };
+        // an incomplete character at the very start is passed on as it is
+        return if start > 0 && start + width > bytes.len() { start } else { bytes.len() };
+    }
+    bytes.len()
+}
+
+pub(crate) fn kill_child(child: &Mutex<Child>) {
+    if let Ok(mut child) = child.lock() {
+        let _ = child.kill();
+    }
+}
+
+/// Kills `child` and everything it started, such as the test binaries run
+/// by `cargo test`. `child` must have been spawned as the leader of its own
+/// process group.
+pub(crate) fn kill_process_group(child: &Mutex<Child>) {
+    if let Ok(child) = child.lock() {
+        // the group has the same id as its leader
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cap_streamed_text() {
+        let mut cap = OutputCap::new(8);
+        assert_eq!(cap.take("hello\n").as_deref(), Some("hello\n"));
+        assert_eq!(cap.take("wörld\n"), Some(format!("w{}", TRUNCATION_MARKER)));
+        assert!(cap.is_truncated());
+        assert_eq!(cap.take("more\n"), None);
+    }
+
+    #[test]
+    fn merge_streams() {
+        let lines = merge_lines("one\ntwo".as_bytes(), "three\n".as_bytes(), 100)
+            .iter()
+            .collect::<Vec<_>>();
+        let stdout = lines.iter().filter(|(s, _)| *s == Stream::Stdout).map(|(_, l)| l.as_str());
+        assert_eq!(stdout.collect::<Vec<_>>(), vec!["one\n", "two"]);
+        assert!(lines.contains(&(Stream::Stderr, "three\n".to_owned())));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn split_long_lines() {
+        // no newline, with a character split by the limit
+        let input = format!("{}é{}", "x".repeat(9), "y".repeat(15));
+        let lines = merge_lines(std::io::Cursor::new(input), "".as_bytes(), 10)
+            .iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec!["x".repeat(9), format!("é{}", "y".repeat(8)), "y".repeat(7)]);
+    }
+
+    #[test]
+    fn cap_collected_text() {
+        let mut exceeded = 0;
+        let input = "a".repeat(20_000);
+        let (text, truncated) = read_capped(input.as_bytes(), 10_000, || exceeded += 1);
+        assert!(truncated);
+        assert_eq!(exceeded, 1);
+        assert_eq!(text.len(), 10_000 + TRUNCATION_MARKER.len());
+
+        let (text, truncated) = read_capped("short".as_bytes(), 10_000, || ());
+        assert!(!truncated);
+        assert_eq!(text, "short");
+    }
+}
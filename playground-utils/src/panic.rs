@@ -0,0 +1,299 @@
+//! Parsing panic messages and backtraces from a program's output.
+
+use std::path::Path;
+
+use crate::compile::snippet_line_for_source;
+use crate::sanitizer::{parse_location, strip_symbol_hash};
+
+/// A panic found in a program's output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PanicReport {
+    /// The name of the panicking thread, e.g. `main` or `<unnamed>`.
+    thread: String,
+    message: String,
+    file: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    /// The backtrace, if `RUST_BACKTRACE` was set. Empty otherwise.
+    frames: Vec<BacktraceFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BacktraceFrame {
+    index: usize,
+    /// The demangled function name, without its hash.
+    function: String,
+    file: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    /// `true` if this frame is in the snippet, as opposed to std or the runtime.
+    user_code: bool,
+    /// The corresponding line in the snippet, if this frame is in user code.
+    snippet_line: Option<usize>,
+}
+
+impl PanicReport {
+    /// Finds and parses the first panic in `output`. Frames are only
+    /// counted as user code if their file is the snippet, given relative to
+    /// the generated crate or as a path in `outdir`.
+    pub fn parse(output: &str, outdir: Option<&Path>) -> Option<Self> {
+        let mut lines = output.lines();
+        let (thread, rest) = lines.by_ref().find_map(parse_headline)?;
+
+        let (message, location) = if let Some(rest) = rest.strip_prefix('\'') {
+            // before rust 1.73: `panicked at 'message', src/main.rs:2:5`
+            let mut text = rest.to_owned();
+            loop {
+                if let Some((message, location)) = split_quoted_message(&text) {
+                    break (message.to_owned(), parse_location(location));
+                }
+                match lines.next() {
+                    Some(line) => {
+                        text.push('\n');
+                        text.push_str(line);
+                    }
+                    None => break (text, None),
+                }
+            }
+        } else {
+            // since rust 1.73: `panicked at src/main.rs:2:5:`, followed by the message
+            let location = parse_location(rest.trim_end_matches(':'));
+            let mut message = Vec::new();
+            for line in lines.by_ref() {
+                if line.starts_with("note: ") || line == "stack backtrace:" {
+                    break;
+                }
+                message.push(line);
+            }
+            (message.join("\n"), location)
+        };
+
+        let frames = match output.lines().position(|l| l == "stack backtrace:") {
+            Some(idx) => parse_backtrace(output.lines().skip(idx + 1), outdir),
+            None => Vec::new(),
+        };
+
+        let (file, line, column) = match location {
+            Some((file, line, column)) => (Some(file), Some(line), column),
+            None => (None, None, None),
+        };
+        Some(PanicReport { thread, message, file, line, column, frames })
+    }
+}
+
+/// Matches `thread 'main' panicked at ...` or, in newer versions,
+/// `thread 'main' (1234) panicked at ...`, returning the thread name and
+/// the rest of the line.
+fn parse_headline(line: &str) -> Option<(String, &str)> {
+    let rest = line.strip_prefix("thread '")?;
+    let (thread, rest) = rest.split_once("' ")?;
+    let rest = match rest.strip_prefix('(') {
+        Some(rest) => rest.split_once(") ")?.1,
+        None => rest,
+    };
+    Some((thread.to_owned(), rest.strip_prefix("panicked at ")?))
+}
+
+/// Splits `message', src/main.rs:2:5` into the message and location.
+fn split_quoted_message(text: &str) -> Option<(&str, &str)> {
+    let idx = text.rfind("', ")?;
+    let location = &text[idx + 3..];
+    parse_location(location)?;
+    Some((&text[..idx], location))
+}
+
+/// Parses backtrace frames in either the short format,
+///
+/// ```text
+///    2: playground::main
+///              at ./src/main.rs:2:5
+/// ```
+///
+/// or the full format, where inlined functions share an index:
+///
+/// ```text
+///    2:     0x55d4c8a0b1c2 - playground::main::h0123456789abcdef
+///                                at /tmp/out/src/main.rs:2:5
+///                            core::ops::function::FnOnce::call_once
+/// ```
+fn parse_backtrace<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    outdir: Option<&Path>,
+) -> Vec<BacktraceFrame> {
+    let mut frames: Vec<BacktraceFrame> = Vec::new();
+    for line in lines.map(str::trim) {
+        if let Some(location) = line.strip_prefix("at ") {
+            if let (Some(frame), Some((file, line, column))) =
+                (frames.last_mut(), parse_location(location))
+            {
+                frame.snippet_line = snippet_line_for_source(&file, line, outdir);
+                frame.user_code = frame.snippet_line.is_some();
+                frame.file = Some(file);
+                frame.line = Some(line);
+                frame.column = column;
+            }
+            continue;
+        }
+
+        let (index, symbol) = match line.split_once(": ") {
+            Some((index, symbol)) if index.chars().all(|c| c.is_ascii_digit()) => {
+                match index.parse() {
+                    Ok(index) => (index, symbol.trim()),
+                    Err(_) => break,
+                }
+            }
+            // an inlined frame, in the full format
+            _ if is_symbol(line) => match frames.last() {
+                Some(frame) => (frame.index, line),
+                None => break,
+            },
+            _ => break,
+        };
+        let symbol = match symbol.split_once(" - ") {
+            Some((address, name)) if address.starts_with("0x") => name,
+            _ => symbol,
+        };
+
+        frames.push(BacktraceFrame {
+            index,
+            function: strip_crate_hashes(strip_symbol_hash(symbol)),
+            file: None,
+            line: None,
+            column: None,
+            user_code: false,
+            snippet_line: None,
+        });
+    }
+    frames
+}
+
+/// Removes the disambiguators from v0 symbols, e.g. `std[e28293b1aa0f68bd]::`.
+fn strip_crate_hashes(symbol: &str) -> String {
+    let mut result = String::with_capacity(symbol.len());
+    let mut rest = symbol;
+    while let Some(start) = rest.find('[') {
+        let end = rest[start..].find(']').map(|end| start + end);
+        match end {
+            Some(end) if rest[start + 1..end].chars().all(|c| c.is_ascii_hexdigit()) => {
+                result.push_str(&rest[..start]);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                result.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Anything after the backtrace is either a note or more output.
+fn is_symbol(line: &str) -> bool {
+    !(line.is_empty() || line.starts_with("note: ") || line.starts_with("thread '"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_new_format() {
+        let output = "\
+thread 'main' panicked at src/main.rs:3:5:
+index out of bounds: the len is 3
+but the index is 10
+stack backtrace:
+   0: rust_begin_unwind
+             at /rustc/abc/library/std/src/panicking.rs:652:5
+   1: core::panicking::panic_fmt
+             at /rustc/abc/library/core/src/panicking.rs:72:14
+   2: playground::main
+             at ./src/main.rs:3:5
+   3: core::ops::function::FnOnce::call_once
+             at /rustc/abc/library/core/src/ops/function.rs:250:5
+note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace.
+";
+        let report = PanicReport::parse(output, None).unwrap();
+        assert_eq!(report.thread, "main");
+        assert_eq!(report.message, "index out of bounds: the len is 3\nbut the index is 10");
+        assert_eq!(report.file.as_deref(), Some("src/main.rs"));
+        assert_eq!((report.line, report.column), (Some(3), Some(5)));
+        assert_eq!(report.frames.len(), 4);
+        assert_eq!(
+            report.frames[2],
+            BacktraceFrame {
+                index: 2,
+                function: "playground::main".into(),
+                file: Some("./src/main.rs".into()),
+                line: Some(3),
+                column: Some(5),
+                user_code: true,
+                snippet_line: Some(3),
+            }
+        );
+        assert!(!report.frames[3].user_code);
+    }
+
+    #[test]
+    fn parse_old_format() {
+        let output = "thread '<unnamed>' panicked at 'it's broken', src/main.rs:2:5\n\
+                      note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace.";
+        let report = PanicReport::parse(output, None).unwrap();
+        assert_eq!(report.thread, "<unnamed>");
+        assert_eq!(report.message, "it's broken");
+        assert_eq!(report.line, Some(2));
+        assert!(report.frames.is_empty());
+    }
+
+    #[test]
+    fn parse_full_backtrace() {
+        let frames = parse_backtrace(
+            "   0:     0x55d4c8a0b1c2 - playground::main::h0123456789abcdef
+                               at /tmp/out/src/main.rs:2:5
+                           core::ops::function::FnOnce::call_once
+                               at /rustc/abc/library/core/src/ops/function.rs:250:5
+   1:     0x55d4c8a0b1d3 - main
+"
+            .lines(),
+            Some(Path::new("/tmp/out")),
+        );
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].function, "playground::main");
+        assert!(frames[0].user_code);
+        assert_eq!((frames[1].index, frames[1].line), (0, Some(250)));
+        assert_eq!(frames[2].function, "main");
+    }
+
+    #[test]
+    fn dependency_frames_are_not_user_code() {
+        let frames = parse_backtrace(
+            "   0: itoa::Buffer::format
+             at /home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/itoa-1.0.9/src/lib.rs:80:9
+   1: std::rt::lang_start
+             at /rustc/abc/library/std/src/lib.rs:10:5
+   2: playground::main
+             at /tmp/out/src/main.rs:4:5
+"
+            .lines(),
+            Some(Path::new("/tmp/out")),
+        );
+        assert_eq!(frames.len(), 3);
+        assert!(!frames[0].user_code);
+        assert_eq!(frames[0].snippet_line, None);
+        assert!(!frames[1].user_code);
+        assert_eq!(frames[2].snippet_line, Some(4));
+    }
+
+    #[test]
+    fn newer_headline_and_symbols() {
+        let (thread, rest) =
+            parse_headline("thread 'main' (10797) panicked at src/main.rs:4:21:").unwrap();
+        assert_eq!((thread.as_str(), rest), ("main", "src/main.rs:4:21:"));
+        assert_eq!(
+            strip_crate_hashes("<std[e28293b1aa0f68bd]::Foo as core[c1f1]::fmt::Display>::fmt"),
+            "<std::Foo as core::fmt::Display>::fmt"
+        );
+        assert_eq!(strip_crate_hashes("<[u8]>::len"), "<[u8]>::len");
+    }
+}
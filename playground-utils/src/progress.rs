@@ -0,0 +1,152 @@
+//! Parsing cargo's human-readable status lines into structured progress events.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A structured event describing the progress of a cargo invocation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BuildProgress {
+    /// Cargo is updating the registry index or resolving dependencies.
+    Resolving,
+    /// A crate is being downloaded.
+    Downloading { name: String, version: String },
+    /// A crate is being compiled. `index` starts at 1; `total` is the number
+    /// of packages in the lockfile, if it could be read.
+    Compiling { name: String, version: String, index: usize, total: Option<usize> },
+    /// The build finished after this many seconds.
+    Finished { elapsed_secs: f64 },
+    /// Cargo is running a produced binary, such as a test harness.
+    Running { target: String },
+}
+
+/// Tracks state across lines, so we can number compilation units.
+pub(crate) struct ProgressTracker {
+    lockfile: PathBuf,
+    compiled: usize,
+    total: Option<usize>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(outdir: &Path) -> Self {
+        ProgressTracker { lockfile: outdir.join("Cargo.lock"), compiled: 0, total: None }
+    }
+
+    /// Returns the event described by this line of cargo's stderr, if any.
+    pub(crate) fn parse_line(&mut self, line: &str) -> Option<BuildProgress> {
+        let line = line.trim();
+        let mut split = line.splitn(2, ' ');
+        let verb = split.next()?;
+        let rest = split.next().unwrap_or("").trim();
+
+        match verb {
+            "Updating" | "Locking" | "Resolving" => Some(BuildProgress::Resolving),
+            "Downloading" | "Downloaded" => {
+                let (name, version) = name_and_version(rest)?;
+                Some(BuildProgress::Downloading { name, version })
+            }
+            "Compiling" | "Checking" | "Documenting" => {
+                let (name, version) = name_and_version(rest)?;
+                if self.total.is_none() {
+                    self.total = count_lockfile_packages(&self.lockfile);
+                }
+                self.compiled += 1;
+                let total = self.total.map(|t| t.max(self.compiled));
+                Some(BuildProgress::Compiling { name, version, index: self.compiled, total })
+            }
+            "Finished" => {
+                let elapsed = rest.rsplit(" in ").next()?;
+                let elapsed_secs = parse_elapsed(elapsed)?;
+                Some(BuildProgress::Finished { elapsed_secs })
+            }
+            "Running" => Some(BuildProgress::Running { target: rest.trim_matches('`').into() }),
+            _ => None,
+        }
+    }
+}
+
+/// Parses strings of the form `serde v1.0.1 (extra)`.
+fn name_and_version(s: &str) -> Option<(String, String)> {
+    let mut tokens = s.split_whitespace();
+    let name = tokens.next()?;
+    let version = tokens.next()?;
+    if !version.starts_with('v') {
+        return None;
+    }
+    Some((name.to_owned(), version.trim_start_matches('v').to_owned()))
+}
+
+/// Cargo reports durations as `0.35s`, `1m 02s`, or on older versions, `2.35 secs`.
+fn parse_elapsed(s: &str) -> Option<f64> {
+    let mut total = None;
+    for part in s.split_whitespace().filter(|p| *p != "secs") {
+        let (num, scale) = match part.chars().last()? {
+            'h' => (&part[..part.len() - 1], 3600.0),
+            'm' => (&part[..part.len() - 1], 60.0),
+            's' => (&part[..part.len() - 1], 1.0),
+            _ => (part, 1.0),
+        };
+        let value = num.parse::<f64>().ok()?;
+        total = Some(total.unwrap_or(0.0) + value * scale);
+    }
+    total
+}
+
+fn count_lockfile_packages(path: &Path) -> Option<usize> {
+    let lockfile = fs::read_to_string(path).ok()?;
+    Some(lockfile.lines().filter(|l| l.trim() == "[[package]]").count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_progress_lines() {
+        let mut tracker = ProgressTracker::new(Path::new("/no/such/dir"));
+        assert_eq!(
+            tracker.parse_line("    Updating crates.io index"),
+            Some(BuildProgress::Resolving)
+        );
+        assert_eq!(
+            tracker.parse_line("  Downloaded serde v1.0.99"),
+            Some(BuildProgress::Downloading { name: "serde".into(), version: "1.0.99".into() })
+        );
+        assert_eq!(
+            tracker.parse_line("   Compiling serde v1.0.99\n"),
+            Some(BuildProgress::Compiling {
+                name: "serde".into(),
+                version: "1.0.99".into(),
+                index: 1,
+                total: None,
+            })
+        );
+        assert_eq!(
+            tracker.parse_line("   Compiling playground v0.0.0 (/tmp/playground)"),
+            Some(BuildProgress::Compiling {
+                name: "playground".into(),
+                version: "0.0.0".into(),
+                index: 2,
+                total: None,
+            })
+        );
+        assert_eq!(
+            tracker.parse_line("    Finished dev [unoptimized + debuginfo] target(s) in 1m 02s"),
+            Some(BuildProgress::Finished { elapsed_secs: 62.0 })
+        );
+        assert_eq!(
+            tracker.parse_line("     Running `target/debug/playground`"),
+            Some(BuildProgress::Running { target: "target/debug/playground".into() })
+        );
+        assert_eq!(tracker.parse_line("warning: unused variable: `x`"), None);
+        assert_eq!(tracker.parse_line(""), None);
+    }
+
+    #[test]
+    fn elapsed_formats() {
+        assert_eq!(parse_elapsed("0.35s"), Some(0.35));
+        assert_eq!(parse_elapsed("2.5 secs"), Some(2.5));
+        assert_eq!(parse_elapsed("1m 30s"), Some(90.0));
+        assert_eq!(parse_elapsed("soon"), None);
+    }
+}
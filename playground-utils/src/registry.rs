@@ -0,0 +1,358 @@
+//! Completing inline dependencies from the local cache of the crates.io
+//! index, which cargo keeps up to date as it builds, so this works offline.
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+use serde_json::Value;
+
+use crate::compile::inline_dependency_names;
+use crate::error::Error;
+
+/// A crate name matching a prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrateCompletion {
+    name: String,
+    /// How many times the crate has been recorded with `record_crate_usage`.
+    uses: u32,
+}
+
+/// A published version of a crate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrateVersion {
+    version: String,
+    yanked: bool,
+    /// Feature names, including the implicit features of optional
+    /// dependencies, sorted.
+    features: Vec<String>,
+}
+
+impl CrateCompletion {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl CrateVersion {
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+}
+
+/// Returns up to `limit` crates in the index cache whose names start with
+/// `prefix`, treating `-` and `_` as the same. An exact match comes first,
+/// then crates by how often they have been used, then shorter names.
+///
+/// `usage_file` is the file written by `record_crate_usage`, if any.
+pub fn complete_crate_names(
+    prefix: &str,
+    usage_file: Option<&Path>,
+    limit: usize,
+) -> Vec<CrateCompletion> {
+    let usage = usage_file.map(read_usage).unwrap_or_default();
+    let names =
+        cache_dirs().iter().flat_map(|dir| crate_names(dir, prefix)).collect::<BTreeSet<_>>();
+    rank_completions(prefix, names, &usage, limit)
+}
+
+/// Returns the versions of `name` in the index cache, newest first. `name`
+/// may be spelled with `-` where the crate has `_`, or the other way around.
+pub fn crate_versions(name: &str) -> Result<Vec<CrateVersion>, Error> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_".contains(&b)) {
+        return Err(Error::UnknownCrate(name.to_owned()));
+    }
+    let spellings = [name.to_owned(), name.replace('-', "_"), name.replace('_', "-")];
+    cache_dirs()
+        .iter()
+        .find_map(|dir| spellings.iter().find_map(|name| fs::read(dir.join(index_path(name))).ok()))
+        .map(|data| parse_cache_file(&data))
+        .ok_or_else(|| Error::UnknownCrate(name.to_owned()))
+}
+
+/// Counts the crates used by `code`'s `//~ use` lines in `usage_file`, a
+/// json object of crate names to counts, creating it if needed.
+pub fn record_crate_usage(usage_file: &Path, code: &str) -> Result<(), Error> {
+    let mut usage = read_usage(usage_file);
+    for name in inline_dependency_names(code) {
+        *usage.entry(normalize(name)).or_insert(0) += 1;
+    }
+    let json = serde_json::to_string(&usage).unwrap();
+    fs::write(usage_file, json).map_err(|_| Error::CreateOutputFailed(usage_file.to_owned()))
+}
+
+fn read_usage(usage_file: &Path) -> HashMap<String, u32> {
+    // a missing or corrupt file just means we have no history
+    fs::read_to_string(usage_file)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn rank_completions(
+    prefix: &str,
+    names: BTreeSet<String>,
+    usage: &HashMap<String, u32>,
+    limit: usize,
+) -> Vec<CrateCompletion> {
+    let prefix = normalize(prefix);
+    let mut matches = names
+        .into_iter()
+        .filter(|name| normalize(name).starts_with(&prefix))
+        .map(|name| {
+            let uses = usage.get(&normalize(&name)).copied().unwrap_or(0);
+            CrateCompletion { name, uses }
+        })
+        .collect::<Vec<_>>();
+    matches
+        .sort_by_key(|c| (normalize(&c.name) != prefix, std::cmp::Reverse(c.uses), c.name.len()));
+    matches.truncate(limit);
+    matches
+}
+
+/// Crate names are case-insensitive, and `-` and `_` are interchangeable.
+fn normalize(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+/// The `.cache` directory of each registry index cargo has used.
+fn cache_dirs() -> Vec<PathBuf> {
+    let cargo_home = match env::var_os("CARGO_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => match dirs::home_dir() {
+            Some(home) => home.join(".cargo"),
+            None => return Vec::new(),
+        },
+    };
+    let index = cargo_home.join("registry").join("index");
+    let registries = match fs::read_dir(index) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    registries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path().join(".cache"))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+/// Every crate with a cache file in the directories that can hold names
+/// starting with `prefix`; the file names are the crate names.
+fn crate_names(cache_dir: &Path, prefix: &str) -> Vec<String> {
+    let prefix = normalize(prefix);
+    let mut names = Vec::new();
+    let mut dirs = vec![(cache_dir.to_owned(), Vec::new())];
+    while let Some((dir, shard)) = dirs.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().filter_map(Result::ok) {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = entry.path();
+            if path.is_dir() {
+                let mut shard = shard.clone();
+                shard.push(name);
+                if shard_may_match(&shard, &prefix) {
+                    dirs.push((path, shard));
+                }
+            } else {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Whether the index directory `shard`, given as its path components in
+/// the cache, can hold crates whose normalized names start with `prefix`.
+/// See `index_path` for the layout.
+fn shard_may_match(shard: &[String], prefix: &str) -> bool {
+    // either may be the longer, as the prefix may be shorter than a shard
+    let agrees = |offset: usize, part: &str| {
+        let part = normalize(part);
+        let wanted = prefix.get(offset..).unwrap_or_default();
+        part.starts_with(wanted) || wanted.starts_with(&part)
+    };
+    match shard {
+        [len] if len == "1" => prefix.len() <= 1,
+        [len] if len == "2" => prefix.len() <= 2,
+        [len] if len == "3" => prefix.len() <= 3,
+        [len, first] if len == "3" => agrees(0, first),
+        [ab] => agrees(0, ab),
+        [ab, cd] => agrees(0, ab) && agrees(2, cd),
+        _ => false,
+    }
+}
+
+/// The path of a crate's entry in the index, e.g. `se/rd/serde`.
+fn index_path(name: &str) -> PathBuf {
+    let name = name.to_ascii_lowercase();
+    match name.len() {
+        1 => Path::new("1").join(&name),
+        2 => Path::new("2").join(&name),
+        3 => Path::new("3").join(&name[..1]).join(&name),
+        _ => Path::new(&name[..2]).join(&name[2..4]).join(&name),
+    }
+}
+
+/// Cache files start with a format version byte, then (from version 2) a
+/// four byte index version, then a null-terminated header. After that come
+/// pairs of null-terminated strings: a version, and its json index entry.
+fn parse_cache_file(data: &[u8]) -> Vec<CrateVersion> {
+    let start = match data.first() {
+        Some(1) => 1,
+        Some(_) => 5,
+        None => return Vec::new(),
+    };
+    let mut fields = data.get(start..).unwrap_or_default().split(|b| *b == 0).skip(1);
+
+    let mut versions = Vec::new();
+    while let (Some(_), Some(json)) = (fields.next(), fields.next()) {
+        if let Some(version) = serde_json::from_slice(json).ok().and_then(parse_entry) {
+            versions.push(version);
+        }
+    }
+
+    versions.sort_by(|a, b| {
+        let a = Version::parse(&a.version).ok();
+        let b = Version::parse(&b.version).ok();
+        b.cmp(&a)
+    });
+    versions
+}
+
+fn parse_entry(entry: Value) -> Option<CrateVersion> {
+    let version = entry["vers"].as_str()?.to_owned();
+    let yanked = entry["yanked"].as_bool().unwrap_or(false);
+
+    let mut features = BTreeSet::new();
+    let mut explicit_deps = BTreeSet::new();
+    for table in &[&entry["features"], &entry["features2"]] {
+        for (feature, enables) in table.as_object().into_iter().flatten() {
+            features.insert(feature.to_owned());
+            let enables = enables.as_array().into_iter().flatten().filter_map(Value::as_str);
+            explicit_deps.extend(enables.filter_map(|f| f.strip_prefix("dep:")));
+        }
+    }
+    // optional dependencies are features, unless a feature enables them
+    // with `dep:`
+    for dep in entry["deps"].as_array().into_iter().flatten() {
+        match dep["name"].as_str() {
+            Some(name) if dep["optional"] == true && !explicit_deps.contains(name) => {
+                features.insert(name.to_owned());
+            }
+            _ => (),
+        }
+    }
+
+    Some(CrateVersion { version, yanked, features: features.into_iter().collect() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_file(entries: &[&str]) -> Vec<u8> {
+        let mut data = vec![3, 2, 0, 0, 0];
+        data.extend_from_slice(b"etag\0");
+        for entry in entries {
+            let version: Value = serde_json::from_str(entry).unwrap();
+            data.extend_from_slice(version["vers"].as_str().unwrap().as_bytes());
+            data.push(0);
+            data.extend_from_slice(entry.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    #[test]
+    fn parse_versions() {
+        let data = cache_file(&[
+            r#"{"name":"rand","vers":"0.9.0","deps":[],"features":{"std":[]},"yanked":false}"#,
+            r#"{"name":"rand","vers":"0.10.0","deps":[
+                {"name":"log","optional":true},{"name":"serde","optional":true}
+            ],"features":{"default":["std"],"std":[]},"features2":{"serde":["dep:serde"]},
+            "yanked":true}"#,
+        ]);
+        let versions = parse_cache_file(&data);
+        assert_eq!(
+            versions,
+            vec![
+                CrateVersion {
+                    version: "0.10.0".into(),
+                    yanked: true,
+                    features: vec!["default".into(), "log".into(), "serde".into(), "std".into()],
+                },
+                CrateVersion {
+                    version: "0.9.0".into(),
+                    yanked: false,
+                    features: vec!["std".into()]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rank_by_usage() {
+        let names = ["serde", "serde_json", "serde-derive", "serde_yaml", "sera"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut usage = HashMap::new();
+        usage.insert("serde_json".to_owned(), 3);
+        usage.insert("serde_derive".to_owned(), 1);
+
+        let ranked = rank_completions("Serde", names, &usage, 3);
+        let ranked = ranked.iter().map(CrateCompletion::name).collect::<Vec<_>>();
+        assert_eq!(ranked, vec!["serde", "serde_json", "serde-derive"]);
+    }
+
+    #[test]
+    fn record_usage() {
+        let dir = tempdir::TempDir::new("registry").unwrap();
+        let usage_file = dir.path().join("usage.json");
+        record_crate_usage(&usage_file, "//~ use serde\n//~ use Rand = 0.7\nfn main() {}").unwrap();
+        record_crate_usage(&usage_file, "//~ use rand\n//~ use bogus line here").unwrap();
+        let usage = read_usage(&usage_file);
+        assert_eq!(usage.get("rand"), Some(&2));
+        assert_eq!(usage.get("serde"), Some(&1));
+        assert_eq!(usage.len(), 2);
+    }
+
+    #[test]
+    fn walk_only_matching_shards() {
+        let dir = tempdir::TempDir::new("registry_shards").unwrap();
+        for name in &["a", "se", "ser", "serde", "serde_json", "serde-derive", "sera", "syn"] {
+            let path = dir.path().join(index_path(name));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        // the rest of the shard is filtered by `rank_completions`
+        let mut names = crate_names(dir.path(), "serde-");
+        names.sort();
+        assert_eq!(names, vec!["serde", "serde-derive", "serde_json"]);
+        let mut names = crate_names(dir.path(), "se");
+        names.sort();
+        assert_eq!(names, vec!["se", "ser", "sera", "serde", "serde-derive", "serde_json", "syn"]);
+        // names of up to three letters are sharded by length and first letter
+        let mut names = crate_names(dir.path(), "sy");
+        names.sort();
+        assert_eq!(names, vec!["se", "ser", "syn"]);
+        assert_eq!(crate_names(dir.path(), "").len(), 8);
+    }
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a"), Path::new("1/a"));
+        assert_eq!(index_path("cc"), Path::new("2/cc"));
+        assert_eq!(index_path("syn"), Path::new("3/s/syn"));
+        assert_eq!(index_path("Serde"), Path::new("se/rd/serde"));
+    }
+}
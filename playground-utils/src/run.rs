@@ -0,0 +1,78 @@
+//! Running compiled programs.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::output::{collect_capped, OutputLimit};
+use crate::panic::PanicReport;
+use crate::sandbox::Isolation;
+
+/// The output of running a compiled program.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunResult {
+    pub(crate) success: bool,
+    /// The exit code, or `None` if the process was terminated by a signal.
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    /// How the program was isolated, if it was run in the sandbox.
+    pub(crate) isolation: Option<Isolation>,
+    /// `true` if stdout or stderr exceeded the output limit.
+    pub(crate) truncated: bool,
+    /// How long the program ran for, in seconds.
+    pub(crate) elapsed_secs: f64,
+    /// The panic reported in stderr, if the program panicked.
+    pub(crate) panic: Option<PanicReport>,
+}
+
+impl RunResult {
+    pub(crate) fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// Runs `executable` to completion in `working_dir`, collecting its output.
+/// The executable should have been built in `working_dir`, so that panics
+/// can be traced back to the snippet.
+pub fn run_program(
+    executable: &Path,
+    working_dir: &Path,
+    backtrace: bool,
+    limit: &OutputLimit,
+) -> Result<RunResult, Error> {
+    let mut command = Command::new(executable);
+    command.current_dir(working_dir).stdout(Stdio::piped()).stderr(Stdio::piped());
+    if backtrace {
+        command.env("RUST_BACKTRACE", "1");
+    }
+
+    let start = Instant::now();
+    let mut child = command.spawn().map_err(Error::RunFailed)?;
+    let stdout = child.stdout.take().expect("piped stdout must exist");
+    let stderr = child.stderr.take().expect("piped stderr must exist");
+    let child = Arc::new(Mutex::new(child));
+    let stdout_thread = collect_capped(stdout, *limit, &child);
+    let stderr_thread = collect_capped(stderr, *limit, &child);
+
+    let (stdout, stdout_truncated) = stdout_thread.join().unwrap_or_default();
+    let (stderr, stderr_truncated) = stderr_thread.join().unwrap_or_default();
+    let status = child.lock().unwrap().wait().map_err(Error::RunFailed)?;
+    let panic = PanicReport::parse(&stderr, Some(working_dir));
+    Ok(RunResult {
+        success: status.success(),
+        exit_code: status.code(),
+        stdout,
+        stderr,
+        isolation: None,
+        truncated: stdout_truncated || stderr_truncated,
+        elapsed_secs: start.elapsed().as_secs_f64(),
+        panic,
+    })
+}
@@ -92,8 +92,12 @@ fn toolchain_sort<T: AsRef<str>>(v: &mut Vec<T>) {
     fn toolchain_sort_key(s: &str) -> Version {
         use crate::toolchain::NATIVE_TOOLCHAIN;
 
-        // we want nightly-x86_etc to order before nightly-date-x86_etc
-        let s = s.trim_end_matches(NATIVE_TOOLCHAIN);
+        // we want nightly-x86_etc to order before nightly-date-x86_etc, so
+        // the architecture is dropped along with the rest of the triple
+        let s = match s.strip_suffix(NATIVE_TOOLCHAIN) {
+            Some(s) => s.rsplit_once('-').map(|(s, _arch)| s).unwrap_or(s),
+            None => s,
+        };
 
         if s.starts_with("stable") {
             special_version(0, s)
@@ -0,0 +1,566 @@
+//! Running untrusted programs with resource limits and, on Linux,
+//! without network access or write access outside a private directory.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::output::OutputLimit;
+use crate::run::RunResult;
+
+/// Limits applied to a sandboxed program.
+#[derive(Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// CPU time, in seconds.
+    cpu_secs: u64,
+    /// Wall-clock time, in seconds, after which the program is killed.
+    wall_time_secs: u64,
+    /// The maximum size of the address space, in bytes.
+    memory_bytes: u64,
+    /// The maximum size of any file written, in bytes.
+    file_size_bytes: u64,
+    /// The maximum number of processes and threads. On kernels older than
+    /// 5.14 this counts all of the user's processes.
+    max_processes: u64,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            cpu_secs: 10,
+            wall_time_secs: 30,
+            memory_bytes: 1 << 30,
+            file_size_bytes: 64 << 20,
+            max_processes: 64,
+        }
+    }
+}
+
+/// The limit that a sandboxed program exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxLimit {
+    CpuTime,
+    WallTime,
+    Memory,
+    FileSize,
+    Processes,
+}
+
+impl fmt::Display for SandboxLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            SandboxLimit::CpuTime => "CPU time",
+            SandboxLimit::WallTime => "wall-clock time",
+            SandboxLimit::Memory => "memory",
+            SandboxLimit::FileSize => "file size",
+            SandboxLimit::Processes => "process count",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// How a sandboxed program was isolated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// Resource limits and a private working directory.
+    LimitsOnly,
+    /// Resource limits and a private working directory, as well as no
+    /// network access and a read-only view of the rest of the filesystem.
+    Namespaces,
+}
+
+/// Runs `executable` in a fresh temporary directory with the limits in `config`.
+///
+/// Namespaces are used where the kernel permits unprivileged user namespaces;
+/// otherwise only the resource limits apply, which is reflected in the
+/// result's `isolation`. If a limit is exceeded this returns
+/// `Error::SandboxLimitExceeded`, which includes the program's output.
+///
+/// `crate_dir` is the crate the executable was built from, if any, so that
+/// panics can be traced back to the snippet.
+#[cfg(target_os = "linux")]
+pub fn run_sandboxed<P: AsRef<Path>>(
+    executable: P,
+    crate_dir: Option<&Path>,
+    backtrace: bool,
+    config: &SandboxConfig,
+    output_limit: &OutputLimit,
+) -> Result<RunResult, Error> {
+    linux::run_sandboxed(executable.as_ref(), crate_dir, backtrace, config, output_limit)
+}
+
+/// Sandboxing is currently only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn run_sandboxed<P: AsRef<Path>>(
+    _executable: P,
+    _crate_dir: Option<&Path>,
+    _backtrace: bool,
+    _config: &SandboxConfig,
+    _output_limit: &OutputLimit,
+) -> Result<RunResult, Error> {
+    Err(Error::SandboxUnavailable)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::path::Path;
+    use std::process::{Child, Command, ExitStatus, Stdio};
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use libc::{c_int, c_ulong};
+    use tempdir::TempDir;
+
+    use super::{Isolation, SandboxConfig, SandboxLimit};
+    use crate::error::Error;
+    use crate::output::{collect_capped, kill_child, OutputLimit};
+    use crate::panic::PanicReport;
+    use crate::run::RunResult;
+
+    #[cfg(target_env = "gnu")]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(target_env = "gnu"))]
+    type Resource = c_int;
+
+    pub(super) fn run_sandboxed(
+        executable: &Path,
+        crate_dir: Option<&Path>,
+        backtrace: bool,
+        config: &SandboxConfig,
+        output_limit: &OutputLimit,
+    ) -> Result<RunResult, Error> {
+        let workdir = TempDir::new("playground_sandbox")
+            .map_err(|_| Error::CreateOutputFailed(std::env::temp_dir()))?;
+
+        let limits = *config;
+        let namespaces = Namespaces::new(workdir.path());
+        let mut command = sandbox_command(executable, workdir.path(), backtrace);
+        // Safety: the closure only makes syscalls, and does not allocate.
+        unsafe {
+            command.pre_exec(move || {
+                apply_limits(&limits)?;
+                namespaces.enter()
+            });
+        }
+
+        // if namespaces aren't available, fall back to limits alone
+        let start = Instant::now();
+        let (mut child, isolation) = match command.spawn() {
+            Ok(child) => (child, Isolation::Namespaces),
+            Err(_) => {
+                let mut command = sandbox_command(executable, workdir.path(), backtrace);
+                unsafe {
+                    command.pre_exec(move || apply_limits(&limits));
+                }
+                (command.spawn().map_err(Error::RunFailed)?, Isolation::LimitsOnly)
+            }
+        };
+
+        let stdout = child.stdout.take().expect("piped stdout must exist");
+        let stderr = child.stderr.take().expect("piped stderr must exist");
+        let child = Arc::new(Mutex::new(child));
+        let stdout_thread = collect_capped(stdout, *output_limit, &child);
+        let stderr_thread = collect_capped(stderr, *output_limit, &child);
+
+        let (status, timed_out, cpu_time) = wait_with_timeout(&child, config.wall_time_secs)?;
+        let (stdout, stdout_truncated) = stdout_thread.join().unwrap_or_default();
+        let (stderr, stderr_truncated) = stderr_thread.join().unwrap_or_default();
+        let panic = PanicReport::parse(&stderr, crate_dir);
+        let result = RunResult {
+            success: status.success(),
+            exit_code: status.code(),
+            stdout,
+            stderr,
+            isolation: Some(isolation),
+            truncated: stdout_truncated || stderr_truncated,
+            elapsed_secs: start.elapsed().as_secs_f64(),
+            panic,
+        };
+
+        match detect_violation(status, &result.stderr, timed_out, cpu_time, config) {
+            Some(limit) => Err(Error::SandboxLimitExceeded(limit, Box::new(result))),
+            None => Ok(result),
+        }
+    }
+
+    fn sandbox_command(executable: &Path, workdir: &Path, backtrace: bool) -> Command {
+        let mut command = Command::new(executable);
+        command
+            .current_dir(workdir)
+            .env_clear()
+            .env("HOME", workdir)
+            .env("TMPDIR", workdir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if backtrace {
+            command.env("RUST_BACKTRACE", "1");
+        }
+        command
+    }
+
+    /// Waits for the child, killing it if it outlives `timeout_secs`.
+    /// Returns the exit status, whether we killed it, and the CPU time it
+    /// used if it exited by itself.
+    fn wait_with_timeout(
+        child: &Mutex<Child>,
+        timeout_secs: u64,
+    ) -> Result<(ExitStatus, bool, Duration), Error> {
+        let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+        loop {
+            {
+                let mut child = child.lock().unwrap();
+                if let Some(cpu_time) = exited_cpu_time(&child).map_err(Error::RunFailed)? {
+                    let status = child.wait().map_err(Error::RunFailed)?;
+                    return Ok((status, false, cpu_time));
+                }
+            }
+            if Instant::now() >= deadline {
+                kill_child(child);
+                let status = child.lock().unwrap().wait().map_err(Error::RunFailed)?;
+                return Ok((status, true, Duration::default()));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// If the child has exited, returns the CPU time it used, leaving it to
+    /// be reaped by `Child::wait`. The `waitid` wrapper in libc has no
+    /// rusage argument, so this makes the syscall directly.
+    fn exited_cpu_time(child: &Child) -> io::Result<Option<Duration>> {
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                child.id(),
+                &mut info as *mut libc::siginfo_t,
+                flags,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+        let secs = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        Ok(Some(secs(usage.ru_utime) + secs(usage.ru_stime)))
+    }
+
+    /// Works out which limit, if any, caused the program to fail.
+    fn detect_violation(
+        status: ExitStatus,
+        stderr: &str,
+        timed_out: bool,
+        cpu_time: Duration,
+        config: &SandboxConfig,
+    ) -> Option<SandboxLimit> {
+        if timed_out {
+            return Some(SandboxLimit::WallTime);
+        }
+
+        match status.signal() {
+            Some(libc::SIGXCPU) => return Some(SandboxLimit::CpuTime),
+            // SIGKILL is sent when the hard CPU limit is reached, but also
+            // by the OOM killer, or the program itself
+            Some(libc::SIGKILL) if cpu_time >= Duration::from_secs(config.cpu_secs) => {
+                return Some(SandboxLimit::CpuTime)
+            }
+            Some(libc::SIGXFSZ) => return Some(SandboxLimit::FileSize),
+            _ => (),
+        }
+
+        if status.success() {
+            None
+        } else if stderr.contains("memory allocation of") {
+            Some(SandboxLimit::Memory)
+        } else if stderr.contains("failed to spawn thread") || stderr.contains("fork failed") {
+            Some(SandboxLimit::Processes)
+        } else {
+            None
+        }
+    }
+
+    fn apply_limits(config: &SandboxConfig) -> io::Result<()> {
+        set_limit(libc::RLIMIT_CPU, config.cpu_secs, config.cpu_secs + 1)?;
+        set_limit(libc::RLIMIT_AS, config.memory_bytes, config.memory_bytes)?;
+        set_limit(libc::RLIMIT_FSIZE, config.file_size_bytes, config.file_size_bytes)?;
+        set_limit(libc::RLIMIT_NPROC, config.max_processes, config.max_processes)?;
+        set_limit(libc::RLIMIT_CORE, 0, 0)
+    }
+
+    fn set_limit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+        let limit = libc::rlimit { rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Everything the child needs to enter its namespaces, prepared in the
+    /// parent so that the child does not need to allocate.
+    #[derive(Clone)]
+    struct Namespaces {
+        inner: Arc<NamespacesInner>,
+    }
+
+    struct NamespacesInner {
+        uid_map: Vec<u8>,
+        gid_map: Vec<u8>,
+        workdir: CString,
+        /// Existing mount points, and the flags that must be preserved
+        /// when remounting them. Mounts that are already read-only include
+        /// `MS_RDONLY`.
+        mounts: Vec<(CString, c_ulong)>,
+    }
+
+    impl Namespaces {
+        fn new(workdir: &Path) -> Self {
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            let mounts = fs::read_to_string("/proc/self/mountinfo")
+                .map(|s| parse_mountinfo(&s))
+                .unwrap_or_default();
+            let workdir = CString::new(workdir.as_os_str().as_bytes()).unwrap_or_default();
+            Namespaces {
+                inner: Arc::new(NamespacesInner {
+                    uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+                    gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+                    workdir,
+                    mounts,
+                }),
+            }
+        }
+
+        /// Called in the child: moves into new user, mount and network
+        /// namespaces and makes every existing mount read-only, except for
+        /// a bind mount of the working directory.
+        ///
+        /// This fails if a writable mount cannot be made read-only, so that
+        /// we fall back to `Isolation::LimitsOnly` rather than claim a
+        /// read-only filesystem. Mounts whose path we cannot reach are
+        /// skipped, as they cannot be written through either.
+        fn enter(&self) -> io::Result<()> {
+            let inner = &self.inner;
+            let flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET;
+            check(unsafe { libc::unshare(flags) })?;
+
+            write_file(b"/proc/self/setgroups\0", b"deny")?;
+            write_file(b"/proc/self/uid_map\0", &inner.uid_map)?;
+            write_file(b"/proc/self/gid_map\0", &inner.gid_map)?;
+
+            let root = b"/\0".as_ptr() as *const _;
+            let workdir = inner.workdir.as_ptr();
+            unsafe {
+                let flags = libc::MS_REC | libc::MS_PRIVATE;
+                check(libc::mount(ptr::null(), root, ptr::null(), flags, ptr::null()))?;
+                let flags = libc::MS_BIND | libc::MS_REC;
+                check(libc::mount(workdir, workdir, ptr::null(), flags, ptr::null()))?;
+
+                for (target, preserved) in inner.mounts.iter() {
+                    let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | preserved;
+                    let result =
+                        libc::mount(ptr::null(), target.as_ptr(), ptr::null(), flags, ptr::null());
+                    if result == 0 || preserved & libc::MS_RDONLY != 0 {
+                        continue;
+                    }
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::ENOENT) | Some(libc::EACCES) => (),
+                        _ => return Err(error),
+                    }
+                }
+                // the working directory was entered before the bind mount
+                check(libc::chdir(workdir))
+            }
+        }
+    }
+
+    fn check(result: c_int) -> io::Result<()> {
+        if result != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+        unsafe {
+            let fd = libc::open(path.as_ptr() as *const _, libc::O_WRONLY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let written = libc::write(fd, contents.as_ptr() as *const _, contents.len());
+            libc::close(fd);
+            if written != contents.len() as isize {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts mount points and their locked flags, and whether they are
+    /// read-only, from `/proc/self/mountinfo`.
+    fn parse_mountinfo(text: &str) -> Vec<(CString, c_ulong)> {
+        text.lines()
+            .filter_map(|line| {
+                let mut fields = line.split(' ').skip(4);
+                let mount_point = unescape_mount_point(fields.next()?);
+                let options = fields.next()?;
+                let flags = options.split(',').fold(0, |flags, opt| {
+                    flags
+                        | match opt {
+                            "ro" => libc::MS_RDONLY,
+                            "nosuid" => libc::MS_NOSUID,
+                            "nodev" => libc::MS_NODEV,
+                            "noexec" => libc::MS_NOEXEC,
+                            "noatime" => libc::MS_NOATIME,
+                            "nodiratime" => libc::MS_NODIRATIME,
+                            "relatime" => libc::MS_RELATIME,
+                            _ => 0,
+                        }
+                });
+                Some((CString::new(mount_point).ok()?, flags))
+            })
+            .collect()
+    }
+
+    /// Mount points escape spaces and similar as octal, e.g. `\040`.
+    fn unescape_mount_point(s: &str) -> Vec<u8> {
+        let bytes = s.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\\' && i + 3 < bytes.len() {
+                let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+                if let Ok(c) = u8::from_str_radix(digits, 8) {
+                    out.push(c);
+                    i += 4;
+                    continue;
+                }
+            }
+            out.push(bytes[i]);
+            i += 1;
+        }
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mountinfo() {
+            let text = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                        36 22 0:31 / /my\\040disk rw,nosuid,nodev - tmpfs tmpfs rw\n\
+                        37 22 0:32 / /boot ro,noatime - ext4 /dev/sda2 rw";
+            let mounts = parse_mountinfo(text);
+            assert_eq!(mounts[0], (CString::new("/").unwrap(), libc::MS_RELATIME));
+            assert_eq!(
+                mounts[1],
+                (CString::new("/my disk").unwrap(), libc::MS_NOSUID | libc::MS_NODEV)
+            );
+            assert_eq!(
+                mounts[2],
+                (CString::new("/boot").unwrap(), libc::MS_RDONLY | libc::MS_NOATIME)
+            );
+        }
+
+        #[test]
+        fn sigkill_is_cpu_limit_only_after_cpu_time() {
+            let config = SandboxConfig { cpu_secs: 2, ..SandboxConfig::default() };
+            let killed = ExitStatus::from_raw(libc::SIGKILL);
+            let cpu = Duration::from_millis(2100);
+            assert_eq!(
+                detect_violation(killed, "", false, cpu, &config),
+                Some(SandboxLimit::CpuTime)
+            );
+            let idle = Duration::from_millis(20);
+            assert_eq!(detect_violation(killed, "", false, idle, &config), None);
+            let xcpu = ExitStatus::from_raw(libc::SIGXCPU);
+            assert_eq!(
+                detect_violation(xcpu, "", false, idle, &config),
+                Some(SandboxLimit::CpuTime)
+            );
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn script(dir: &TempDir, body: &str) -> PathBuf {
+        let path = dir.path().join("script.sh");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn writes_outside_workdir() {
+        let dir = TempDir::new("sandbox_writes").unwrap();
+        let outside = dir.path().join("outside.txt");
+        let executable = script(
+            &dir,
+            &format!(
+                "echo inside > inside.txt && cat inside.txt; echo outside > {}",
+                outside.display()
+            ),
+        );
+
+        let config = SandboxConfig::default();
+        let result =
+            run_sandboxed(executable, None, false, &config, &OutputLimit::default()).unwrap();
+        // the working directory is always writable
+        assert_eq!(result.stdout, "inside\n");
+        match result.isolation {
+            Some(Isolation::Namespaces) => {
+                assert!(!result.success);
+                assert!(!outside.exists());
+            }
+            _ => assert!(result.success),
+        }
+    }
+
+    #[test]
+    fn cpu_limit() {
+        let dir = TempDir::new("sandbox_cpu").unwrap();
+        let executable = script(&dir, "while :; do :; done");
+
+        let config = SandboxConfig { cpu_secs: 1, ..SandboxConfig::default() };
+        let result = run_sandboxed(executable, None, false, &config, &OutputLimit::default());
+        match result {
+            Err(Error::SandboxLimitExceeded(SandboxLimit::CpuTime, run)) => {
+                assert!(!run.success);
+                assert!(run.elapsed_secs < config.wall_time_secs as f64);
+            }
+            other => panic!("expected the CPU limit to be exceeded, got {:?}", other),
+        }
+    }
+}
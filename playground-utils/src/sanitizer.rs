@@ -0,0 +1,299 @@
+//! Building with `-Zsanitizer` and parsing the resulting reports.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::compile::snippet_line_for_source;
+use crate::error::Error;
+use crate::toolchain;
+
+/// The sanitizers supported by rustc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sanitizer {
+    Address,
+    Thread,
+    Memory,
+    Leak,
+}
+
+impl Sanitizer {
+    fn as_str(self) -> &'static str {
+        match self {
+            Sanitizer::Address => "address",
+            Sanitizer::Thread => "thread",
+            Sanitizer::Memory => "memory",
+            Sanitizer::Leak => "leak",
+        }
+    }
+
+    /// The targets on which rustc supports this sanitizer.
+    fn supported_targets(self) -> &'static [&'static str] {
+        match self {
+            Sanitizer::Address | Sanitizer::Thread => &[
+                "aarch64-apple-darwin",
+                "aarch64-unknown-linux-gnu",
+                "x86_64-apple-darwin",
+                "x86_64-unknown-freebsd",
+                "x86_64-unknown-linux-gnu",
+            ],
+            Sanitizer::Leak => &[
+                "aarch64-apple-darwin",
+                "aarch64-unknown-linux-gnu",
+                "x86_64-apple-darwin",
+                "x86_64-unknown-linux-gnu",
+            ],
+            Sanitizer::Memory => {
+                &["aarch64-unknown-linux-gnu", "x86_64-unknown-freebsd", "x86_64-unknown-linux-gnu"]
+            }
+        }
+    }
+
+    /// Checks that this sanitizer can be used with this toolchain, returning
+    /// the host target triple that we must build for.
+    pub(crate) fn validate(self, toolchain: &str) -> Result<String, Error> {
+        if !toolchain::is_nightly(toolchain) {
+            return Err(Error::UnsupportedSanitizer(format!(
+                "the {} sanitizer requires a nightly toolchain, not '{}'",
+                self, toolchain
+            )));
+        }
+
+        let target = toolchain::host_triple(toolchain)?;
+        if !self.supported_targets().contains(&target.as_str()) {
+            return Err(Error::UnsupportedSanitizer(format!(
+                "the {} sanitizer is not supported on {}",
+                self, target
+            )));
+        }
+
+        // otherwise cargo only fails once it comes to build std
+        if self == Sanitizer::Memory && !toolchain::has_component(toolchain, "rust-src")? {
+            return Err(Error::UnsupportedSanitizer(format!(
+                "the {} sanitizer rebuilds std, which requires the rust-src component; \
+                 install it with `rustup component add rust-src --toolchain {}`",
+                self, toolchain
+            )));
+        }
+        Ok(target)
+    }
+
+    /// Flags to be passed to rustc.
+    pub(crate) fn rustflags(self) -> Vec<String> {
+        let mut flags = vec![format!("-Zsanitizer={}", self.as_str())];
+        if self == Sanitizer::Memory {
+            flags.push("-Zsanitizer-memory-track-origins".into());
+        }
+        flags
+    }
+
+    /// Extra arguments for cargo. MemorySanitizer needs std to be instrumented
+    /// as well, which requires the `rust-src` component.
+    pub(crate) fn cargo_args(self) -> &'static [&'static str] {
+        match self {
+            Sanitizer::Memory => &["-Zbuild-std"],
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for Sanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A problem found by a sanitizer while running the program.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SanitizerReport {
+    sanitizer: Sanitizer,
+    /// The headline, e.g. `heap-use-after-free on address 0x602000000010 ...`.
+    description: String,
+    /// The `SUMMARY:` line, if present.
+    summary: Option<String>,
+    /// The first stack trace in the report.
+    frames: Vec<StackFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackFrame {
+    index: usize,
+    /// The function name, without its hash.
+    function: String,
+    file: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    /// The corresponding line in the snippet, if this frame is in user code.
+    snippet_line: Option<usize>,
+}
+
+impl SanitizerReport {
+    /// Finds and parses a sanitizer report in the program's stderr, which
+    /// was built in `outdir`.
+    pub(crate) fn parse(sanitizer: Sanitizer, stderr: &str, outdir: &Path) -> Option<Self> {
+        let mut lines = stderr.lines();
+        let description = lines.by_ref().find_map(parse_headline)?;
+
+        let mut frames = Vec::new();
+        let mut summary = None;
+        for line in lines {
+            let line = line.trim();
+            if let Some(frame) = parse_frame(line, outdir) {
+                // frame numbering restarts with each new stack trace
+                if frame.index == 0 && !frames.is_empty() {
+                    summary = summary.or_else(|| find_summary(stderr));
+                    break;
+                }
+                frames.push(frame);
+            } else if line.starts_with("SUMMARY:") {
+                summary = Some(line.to_owned());
+                break;
+            }
+        }
+
+        let summary = summary.or_else(|| find_summary(stderr));
+        Some(SanitizerReport { sanitizer, description, summary, frames })
+    }
+}
+
+/// Matches `==123==ERROR: AddressSanitizer: heap-use-after-free on ...` or
+/// `WARNING: ThreadSanitizer: data race (pid=123)`.
+fn parse_headline(line: &str) -> Option<String> {
+    if !(line.contains("ERROR: ") || line.contains("WARNING: ")) {
+        return None;
+    }
+    let idx = line.find("Sanitizer: ")?;
+    let description = line[idx + "Sanitizer: ".len()..].trim();
+    let description = match description.rfind(" (pid=") {
+        Some(idx) => &description[..idx],
+        None => description,
+    };
+    Some(description.to_owned())
+}
+
+fn find_summary(stderr: &str) -> Option<String> {
+    stderr.lines().map(str::trim).find(|l| l.starts_with("SUMMARY:")).map(String::from)
+}
+
+/// Parses frames like `#0 0x55d4 in playground::main::h0123 /tmp/src/main.rs:4:20`
+/// or, from ThreadSanitizer, `#0 playground::main::h0123 /tmp/src/main.rs:4:20 (playground+0x1)`.
+fn parse_frame(line: &str, outdir: &Path) -> Option<StackFrame> {
+    if !line.starts_with('#') {
+        return None;
+    }
+    let mut split = line[1..].splitn(2, ' ');
+    let index = split.next()?.parse().ok()?;
+    let mut rest = split.next().unwrap_or("").trim();
+
+    if rest.starts_with("0x") {
+        rest = rest.split_once(' ').map(|(_, r)| r).unwrap_or("").trim();
+        rest = rest.trim_start_matches("in ").trim();
+    }
+    if rest.ends_with(')') {
+        if let Some(idx) = rest.rfind(" (") {
+            rest = &rest[..idx];
+        }
+    }
+
+    let (function, location) = match rest.rfind(' ') {
+        Some(idx) => match parse_location(&rest[idx + 1..]) {
+            Some(location) => (&rest[..idx], Some(location)),
+            None => (rest, None),
+        },
+        None => (rest, None),
+    };
+
+    let (file, line, column) = match location {
+        Some((file, line, column)) => (Some(file), Some(line), column),
+        None => (None, None, None),
+    };
+    let snippet_line = file
+        .as_ref()
+        .zip(line)
+        .and_then(|(file, line)| snippet_line_for_source(file, line, Some(outdir)));
+    let function = strip_symbol_hash(function).to_owned();
+    Some(StackFrame { index, function, file, line, column, snippet_line })
+}
+
+/// Parses `path/to/file.rs:4:20` or `path/to/file.rs:4`.
+pub(crate) fn parse_location(s: &str) -> Option<(String, usize, Option<usize>)> {
+    let mut parts = s.rsplitn(3, ':');
+    let last = parts.next()?.parse().ok()?;
+    let second = parts.next()?;
+    match (second.parse::<usize>(), parts.next()) {
+        (Ok(line), Some(file)) => Some((file.to_owned(), line, Some(last))),
+        _ => Some((second.to_owned(), last, None)),
+    }
+}
+
+/// Removes the trailing `::h0123456789abcdef` from a demangled symbol.
+pub(crate) fn strip_symbol_hash(symbol: &str) -> &str {
+    match symbol.rfind("::h") {
+        Some(idx)
+            if symbol.len() - idx == 19
+                && symbol[idx + 3..].chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            &symbol[..idx]
+        }
+        _ => symbol,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_asan_report() {
+        let stderr = r#"=================================================================
+==39249==ERROR: AddressSanitizer: heap-use-after-free on address 0x602000000010 at pc 0x55f5 bp 0x7ffc sp 0x7ffc
+READ of size 4 at 0x602000000010 thread T0
+    #0 0x560b3a5c4b0b in playground::main::h4b5c6d7e8f901234 /tmp/out/src/main.rs:4:20
+    #1 0x560b3a5c4c0c in core::ops::function::FnOnce::call_once::h0123456789abcdef /rustc/abc/library/core/src/ops/function.rs:250:5
+    #2 0x7f0000000000 in __libc_start_main (/lib/x86_64-linux-gnu/libc.so.6+0x21b96)
+
+0x602000000010 is located 0 bytes inside of 4-byte region
+freed by thread T0 here:
+    #0 0x560b3a5c0000 in free
+SUMMARY: AddressSanitizer: heap-use-after-free /tmp/out/src/main.rs:4:20 in playground::main::h4b5c6d7e8f901234
+"#;
+        let report =
+            SanitizerReport::parse(Sanitizer::Address, stderr, Path::new("/tmp/out")).unwrap();
+        assert!(report.description.starts_with("heap-use-after-free on address"));
+        assert!(report.summary.as_ref().unwrap().starts_with("SUMMARY: AddressSanitizer"));
+        assert_eq!(report.frames.len(), 3);
+        assert_eq!(
+            report.frames[0],
+            StackFrame {
+                index: 0,
+                function: "playground::main".into(),
+                file: Some("/tmp/out/src/main.rs".into()),
+                line: Some(4),
+                column: Some(20),
+                snippet_line: Some(4),
+            }
+        );
+        assert_eq!(report.frames[1].snippet_line, None);
+        assert_eq!(report.frames[2].function, "__libc_start_main");
+        assert_eq!(report.frames[2].file, None);
+    }
+
+    #[test]
+    fn parse_tsan_frame() {
+        let frame =
+            parse_frame("#0 playground::main::{{closure}}::h0123456789abcdef /tmp/out/src/main.rs:10:9 (playground+0xa0b1)", Path::new("/tmp/out"))
+                .unwrap();
+        assert_eq!(frame.function, "playground::main::{{closure}}");
+        assert_eq!(frame.line, Some(10));
+        assert_eq!(frame.snippet_line, Some(10));
+    }
+
+    #[test]
+    fn tsan_headline() {
+        assert_eq!(
+            parse_headline("WARNING: ThreadSanitizer: data race (pid=1234)"),
+            Some("data race".into())
+        );
+        assert_eq!(parse_headline("hello world"), None);
+    }
+}
@@ -0,0 +1,392 @@
+//! A queue for compile tasks, so that concurrent requests do not race
+//! on the same output directory.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::compile::{compile_task, CompilerResult, Task};
+use crate::error::Error;
+use crate::gc::ActiveDir;
+use crate::handle::{CancelToken, TaskHandle};
+use crate::output::Stream;
+use crate::progress::BuildProgress;
+
+type OutputCallback = Box<dyn FnMut(Stream, &str) + Send>;
+type ProgressCallback = Box<dyn FnMut(BuildProgress) + Send>;
+type TaskResult = Result<CompilerResult, Error>;
+type Reply = Box<dyn FnOnce(TaskResult) + Send>;
+
+/// Runs compile tasks, one at a time per output directory.
+///
+/// A newly submitted check task supersedes any check tasks still waiting
+/// in the same directory. The last result in each directory is cached, so
+/// resubmitting an unchanged task returns immediately; the output of the
+/// cached build is passed to the new task's callbacks again, so that its
+/// diagnostics are not lost. A cached build is not reused once its
+/// directory or executable has been removed.
+#[derive(Clone, Default)]
+pub struct CompileService {
+    dirs: Arc<Mutex<HashMap<PathBuf, DirState>>>,
+}
+
+/// A handle to a task submitted to a `CompileService`.
+pub struct PendingTask {
+    receiver: Receiver<TaskResult>,
+}
+
+struct Job {
+    task: Task,
+    key: u64,
+    output_callback: OutputCallback,
+    progress_callback: ProgressCallback,
+    /// Set for tasks started with `start`, which can be cancelled.
+    cancel: Option<CancelToken>,
+    /// If `true`, a `run` task's program is run once built, before the next
+    /// job starts.
+    run_program: bool,
+    reply: Reply,
+}
+
+#[derive(Default)]
+struct DirState {
+    running: bool,
+    pending: VecDeque<Job>,
+    /// The last task run in this directory. Any build replaces the
+    /// artifacts of the last one, so this is the only result that is still
+    /// valid.
+    last_result: Option<CachedResult>,
+}
+
+#[derive(Clone)]
+struct CachedResult {
+    key: u64,
+    result: CompilerResult,
+    /// The lines passed to the output callback while building.
+    output: Vec<(Stream, String)>,
+}
+
+impl CompileService {
+    pub fn new() -> Self {
+        CompileService::default()
+    }
+
+    /// Queues `task` to run in `outdir`.
+    pub fn submit<P, F, G>(
+        &self,
+        outdir: P,
+        task: Task,
+        stderr_callback: F,
+        progress_callback: G,
+    ) -> PendingTask
+    where
+        P: Into<PathBuf>,
+        F: FnMut(&str) + Send + 'static,
+        G: FnMut(BuildProgress) + Send + 'static,
+    {
+        self.submit_job(outdir.into(), task, stderr_callback, progress_callback, false)
+    }
+
+    /// Like `submit`, but for `run` tasks the program is run once it is
+    /// built, in the same job, so that no other task can replace it first.
+    /// Its output is the result's `run`.
+    pub fn submit_and_run<P, F, G>(
+        &self,
+        outdir: P,
+        task: Task,
+        stderr_callback: F,
+        progress_callback: G,
+    ) -> PendingTask
+    where
+        P: Into<PathBuf>,
+        F: FnMut(&str) + Send + 'static,
+        G: FnMut(BuildProgress) + Send + 'static,
+    {
+        self.submit_job(outdir.into(), task, stderr_callback, progress_callback, true)
+    }
+
+    fn submit_job<F, G>(
+        &self,
+        outdir: PathBuf,
+        task: Task,
+        stderr_callback: F,
+        progress_callback: G,
+        run_program: bool,
+    ) -> PendingTask
+    where
+        F: FnMut(&str) + Send + 'static,
+        G: FnMut(BuildProgress) + Send + 'static,
+    {
+        let (reply, receiver) = mpsc::channel();
+        let mut stderr_callback = stderr_callback;
+        self.queue(
+            outdir,
+            Job {
+                key: task.cache_key(),
+                task,
+                output_callback: Box::new(move |stream, text| {
+                    if stream == Stream::Stderr {
+                        stderr_callback(text)
+                    }
+                }),
+                progress_callback: Box::new(progress_callback),
+                cancel: None,
+                run_program,
+                reply: Box::new(move |result| {
+                    let _ = reply.send(result);
+                }),
+            },
+        );
+        PendingTask { receiver }
+    }
+
+    /// Queues `task` to run in `outdir`, like `submit`, but returns a handle
+    /// that receives the task's output and progress as events, and that can
+    /// cancel it.
+    pub fn start<P: Into<PathBuf>>(&self, outdir: P, task: Task) -> TaskHandle {
+        let (handle, events, cancel) = TaskHandle::new();
+        let output = events.clone();
+        let progress = events.clone();
+        self.queue(
+            outdir.into(),
+            Job {
+                key: task.cache_key(),
+                task,
+                output_callback: Box::new(move |stream, text| output.output(stream, text)),
+                progress_callback: Box::new(move |event| progress.progress(event)),
+                cancel: Some(cancel),
+                run_program: false,
+                reply: Box::new(move |result| events.done(result)),
+            },
+        );
+        handle
+    }
+
+    fn queue(&self, outdir: PathBuf, job: Job) {
+        let mut dirs = self.dirs.lock().unwrap();
+        let state = dirs.entry(outdir.clone()).or_default();
+
+        if !state.running && !job.run_program {
+            if let Some(cached) = state.cached_result(&outdir, job.key) {
+                drop(dirs);
+                job.finish_cached(cached);
+                return;
+            }
+        }
+
+        if job.task.is_check() {
+            let (superseded, kept): (VecDeque<_>, _) =
+                state.pending.drain(..).partition(|job| job.task.is_check());
+            state.pending = kept;
+            for job in superseded {
+                (job.reply)(Err(Error::Superseded));
+            }
+        }
+
+        state.pending.push_back(job);
+
+        if !state.running {
+            state.running = true;
+            let dirs = self.dirs.clone();
+            thread::spawn(move || run_queue(dirs, outdir));
+        }
+    }
+}
+
+impl PendingTask {
+    /// Blocks until the task has finished.
+    pub fn wait(self) -> Result<CompilerResult, Error> {
+        self.receiver.recv().unwrap_or(Err(Error::TaskAborted))
+    }
+
+    /// Returns the result if the task has finished, without blocking.
+    pub fn try_result(&self) -> Option<Result<CompilerResult, Error>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(Error::TaskAborted)),
+        }
+    }
+}
+
+impl DirState {
+    fn cached_result(&self, outdir: &Path, key: u64) -> Option<CachedResult> {
+        self.last_result.as_ref().filter(|c| c.key == key && c.is_current(outdir)).cloned()
+    }
+}
+
+impl Job {
+    /// Passes on the cached build's output, then replies with its result.
+    fn finish_cached(mut self, cached: CachedResult) {
+        let result = cached.replay(&mut self.output_callback);
+        (self.reply)(Ok(result));
+    }
+}
+
+impl CachedResult {
+    /// `false` if the build has since been removed, e.g. by `collect_garbage`.
+    fn is_current(&self, outdir: &Path) -> bool {
+        outdir.exists() && self.result.executable().map(Path::exists).unwrap_or(true)
+    }
+
+    fn replay(self, output_callback: &mut OutputCallback) -> CompilerResult {
+        for (stream, line) in self.output.iter() {
+            output_callback(*stream, line);
+        }
+        self.result
+    }
+}
+
+/// Runs jobs for `outdir` until its queue is empty.
+fn run_queue(dirs: Arc<Mutex<HashMap<PathBuf, DirState>>>, outdir: PathBuf) {
+    // programs run after the build, so the directory is kept in use until
+    // the queue is empty rather than only while cargo runs
+    let _active = ActiveDir::enter(&outdir);
+    loop {
+        let (job, cached) = {
+            let mut dirs = dirs.lock().unwrap();
+            let state = dirs.get_mut(&outdir).expect("state exists while running");
+            match state.pending.pop_front() {
+                Some(job) => {
+                    let cached = state.cached_result(&outdir, job.key);
+                    (job, cached)
+                }
+                None => {
+                    state.running = false;
+                    return;
+                }
+            }
+        };
+
+        let Job {
+            task,
+            key,
+            mut output_callback,
+            mut progress_callback,
+            cancel,
+            run_program,
+            reply,
+        } = job;
+        let run_program = run_program && task.is_run();
+        let (backtrace, limit) = (task.backtrace(), *task.output_limit());
+        let result = match cached {
+            Some(cached) => Ok(cached.replay(&mut output_callback)),
+            // a task cancelled while it was queued never starts
+            None if cancel.as_ref().map(CancelToken::is_cancelled).unwrap_or(false) => {
+                Err(Error::Cancelled)
+            }
+            None => {
+                let cacheable = task.is_cacheable();
+                let mut output = Vec::new();
+                let result = compile_task(
+                    &outdir,
+                    task,
+                    |stream, line| {
+                        if cacheable {
+                            output.push((stream, line.to_owned()));
+                        }
+                        output_callback(stream, line)
+                    },
+                    &mut progress_callback,
+                    cancel.as_ref(),
+                );
+                let mut dirs = dirs.lock().unwrap();
+                let state = dirs.get_mut(&outdir).expect("state exists while running");
+                state.last_result = match result.as_ref() {
+                    Ok(r) if cacheable => Some(CachedResult { key, result: r.clone(), output }),
+                    _ => None,
+                };
+                result
+            }
+        };
+
+        let result = match result {
+            Ok(mut result) if run_program => {
+                result.run_executable(&outdir, backtrace, &limit).map(|()| result)
+            }
+            other => other,
+        };
+        reply(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::TaskEvent;
+    use tempdir::TempDir;
+
+    fn task_json(task_type: &str, code: &str) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "toolchain": "stable",
+            "code": code,
+            "task_type": task_type,
+            "backtrace": false,
+            "release": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn newer_check_supersedes_pending_check() {
+        let tempdir = TempDir::new("service_supersede").expect("failed to create temp dir");
+        let service = CompileService::new();
+        let outdir = tempdir.path().join("project");
+
+        let run = service.submit(&outdir, task_json("run", "fn main() {}"), |_| {}, |_| {});
+        let first = service.submit(&outdir, task_json("check", "fn main() { }"), |_| {}, |_| {});
+        let second = service.submit(&outdir, task_json("check", "fn main() {  }"), |_| {}, |_| {});
+
+        assert!(matches!(first.wait(), Err(Error::Superseded)));
+        assert!(run.wait().is_ok());
+        assert!(second.wait().is_ok());
+
+        // the last result is cached for unchanged code
+        let key = task_json("check", "fn main() {  }").cache_key();
+        let dirs = service.dirs.lock().unwrap();
+        assert!(dirs[&outdir].cached_result(&outdir, key).is_some());
+        // but not once the directory is gone
+        std::fs::remove_dir_all(&outdir).unwrap();
+        assert!(dirs[&outdir].cached_result(&outdir, key).is_none());
+    }
+
+    #[test]
+    fn cached_failure_replays_diagnostics() {
+        let tempdir = TempDir::new("service_replay").expect("failed to create temp dir");
+        let service = CompileService::new();
+        let outdir = tempdir.path().join("project");
+        let code = "fn main() { let x: u32 = \"no\"; }";
+
+        let first = service.submit(&outdir, task_json("check", code), |_| {}, |_| {});
+        assert!(!first.wait().unwrap().success());
+        let key = task_json("check", code).cache_key();
+        assert!(service.dirs.lock().unwrap()[&outdir].cached_result(&outdir, key).is_some());
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let collected = lines.clone();
+        let callback = move |line: &str| collected.lock().unwrap().push(line.to_owned());
+        let second = service.submit(&outdir, task_json("check", code), callback, |_| {});
+        assert!(!second.wait().unwrap().success());
+        assert!(lines.lock().unwrap().iter().any(|l| l.contains("mismatched types")));
+    }
+
+    #[test]
+    fn started_tasks_share_the_queue() {
+        let tempdir = TempDir::new("service_start").expect("failed to create temp dir");
+        let service = CompileService::new();
+        let outdir = tempdir.path().join("project");
+
+        let handle = service.start(&outdir, task_json("run", "fn main() {}"));
+        let check = service.submit(&outdir, task_json("check", "fn main() { }"), |_| {}, |_| {});
+        let cancelled = service.start(&outdir, task_json("run", "fn main() {  }"));
+        cancelled.cancel();
+
+        let events = handle.collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e, TaskEvent::Stderr(_))));
+        assert!(matches!(events.last(), Some(TaskEvent::Done(done)) if done.is_ok()));
+        assert!(check.wait().unwrap().success());
+        assert!(matches!(cancelled.wait(), Err(Error::Cancelled)));
+    }
+}
@@ -0,0 +1,191 @@
+//! Breaking down the size of a compiled binary by section and symbol.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use rustc_demangle::demangle;
+
+use crate::error::Error;
+
+/// The maximum number of symbols included in a report or comparison.
+const LARGEST_SYMBOLS_COUNT: usize = 50;
+
+/// The size of a binary, broken down by section and by symbol.
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeReport {
+    /// The size of the file on disk.
+    total: u64,
+    /// All sections, largest first.
+    sections: Vec<SectionSize>,
+    /// The largest symbols, largest first.
+    symbols: Vec<SymbolSize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SectionSize {
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SymbolSize {
+    /// The demangled name, without the trailing hash.
+    name: String,
+    size: u64,
+}
+
+/// The difference between two builds, such as `opt-level=s` and `opt-level=3`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeComparison {
+    before: u64,
+    after: u64,
+    /// Sections whose size changed, largest change first.
+    sections: Vec<SizeDelta>,
+    /// Symbols whose size changed, largest change first.
+    symbols: Vec<SizeDelta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeDelta {
+    name: String,
+    before: u64,
+    after: u64,
+    delta: i64,
+}
+
+/// Analyzes the binary at `path`, such as `CompilerResult`'s `executable`.
+pub fn analyze_binary_size<P: AsRef<Path>>(path: P) -> Result<SizeReport, Error> {
+    let mut sizes = BinarySizes::load(path.as_ref())?;
+    sizes.symbols.truncate(LARGEST_SYMBOLS_COUNT);
+    let BinarySizes { total, sections, symbols } = sizes;
+    Ok(SizeReport { total, sections, symbols })
+}
+
+/// Compares two binaries. Because both builds of a snippet write to the same
+/// output path, callers should copy the first executable before rebuilding.
+pub fn compare_binary_sizes<P, Q>(before: P, after: Q) -> Result<SizeComparison, Error>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let before = BinarySizes::load(before.as_ref())?;
+    let after = BinarySizes::load(after.as_ref())?;
+
+    let sections = diff_sizes(
+        before.sections.iter().map(|s| (s.name.as_str(), s.size)),
+        after.sections.iter().map(|s| (s.name.as_str(), s.size)),
+    );
+    let mut symbols = diff_sizes(
+        before.symbols.iter().map(|s| (s.name.as_str(), s.size)),
+        after.symbols.iter().map(|s| (s.name.as_str(), s.size)),
+    );
+    symbols.truncate(LARGEST_SYMBOLS_COUNT);
+
+    Ok(SizeComparison { before: before.total, after: after.total, sections, symbols })
+}
+
+/// Complete size information, before truncation.
+struct BinarySizes {
+    total: u64,
+    sections: Vec<SectionSize>,
+    symbols: Vec<SymbolSize>,
+}
+
+impl BinarySizes {
+    fn load(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(|_| Error::ReadBinaryFailed(path.to_owned()))?;
+        let file =
+            object::File::parse(&*data).map_err(|e| Error::BinaryParseFailed(e.to_string()))?;
+
+        let mut sections = file
+            .sections()
+            .filter_map(|s| Some(SectionSize { name: s.name().ok()?.to_owned(), size: s.size() }))
+            .filter(|s| s.size > 0)
+            .collect::<Vec<_>>();
+        sections.sort_by_key(|s| Reverse(s.size));
+
+        let mut symbols = symbol_sizes(&file);
+        symbols.sort_by_key(|s| Reverse(s.size));
+
+        Ok(BinarySizes { total: data.len() as u64, sections, symbols })
+    }
+}
+
+/// Mach-O does not record symbol sizes, so where a size is missing we treat
+/// each symbol as extending to the next symbol or to the end of its section.
+fn symbol_sizes(file: &object::File) -> Vec<SymbolSize> {
+    let mut defined = file
+        .symbols()
+        .filter(|s| s.is_definition() && matches!(s.kind(), SymbolKind::Text | SymbolKind::Data))
+        .filter_map(|s| Some((s.section_index()?, s.address(), s.size(), s.name().ok()?)))
+        .collect::<Vec<_>>();
+    defined.sort_by_key(|(section, addr, _, _)| (section.0, *addr));
+
+    let mut sizes: HashMap<String, u64> = HashMap::new();
+    for (i, (section, addr, size, name)) in defined.iter().enumerate() {
+        let size = if *size > 0 {
+            *size
+        } else {
+            let next_addr = match defined.get(i + 1) {
+                Some((next_section, next_addr, _, _)) if next_section == section => *next_addr,
+                _ => match file.section_by_index(*section) {
+                    Ok(s) => s.address() + s.size(),
+                    Err(_) => continue,
+                },
+            };
+            next_addr.saturating_sub(*addr)
+        };
+
+        if size > 0 {
+            // demangled names without hashes may collide; we sum them.
+            *sizes.entry(format!("{:#}", demangle(name))).or_default() += size;
+        }
+    }
+
+    sizes.into_iter().map(|(name, size)| SymbolSize { name, size }).collect()
+}
+
+fn diff_sizes<'a>(
+    before: impl Iterator<Item = (&'a str, u64)>,
+    after: impl Iterator<Item = (&'a str, u64)>,
+) -> Vec<SizeDelta> {
+    let mut sizes: HashMap<&str, (u64, u64)> = HashMap::new();
+    for (name, size) in before {
+        sizes.entry(name).or_default().0 += size;
+    }
+    for (name, size) in after {
+        sizes.entry(name).or_default().1 += size;
+    }
+
+    let mut deltas = sizes
+        .into_iter()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(name, (before, after))| SizeDelta {
+            name: name.to_owned(),
+            before,
+            after,
+            delta: after as i64 - before as i64,
+        })
+        .collect::<Vec<_>>();
+    deltas.sort_by(|a, b| b.delta.abs().cmp(&a.delta.abs()).then_with(|| a.name.cmp(&b.name)));
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let before = vec![("main", 10), ("alloc", 100), ("same", 5)];
+        let after = vec![("main", 20), ("fmt", 50), ("same", 5)];
+        let deltas = diff_sizes(before.into_iter(), after.into_iter());
+        let names = deltas.iter().map(|d| d.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["alloc", "fmt", "main"]);
+        assert_eq!(deltas[0].delta, -100);
+        assert_eq!(deltas[2], SizeDelta { name: "main".into(), before: 10, after: 20, delta: 10 });
+    }
+}
@@ -0,0 +1,227 @@
+//! Applying machine-applicable compiler suggestions to a snippet.
+
+use std::path::Path;
+
+use crate::compile::snippet_offset_for_source;
+
+/// A suggestion that can be applied without human judgement.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Suggestion {
+    /// Identifies this suggestion when selecting which to apply.
+    index: usize,
+    /// The help message, e.g. ``remove this `mut` ``.
+    message: String,
+    /// The edits, in snippet byte offsets. These must be applied together.
+    edits: Vec<Edit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Edit {
+    start: usize,
+    end: usize,
+    replacement: String,
+}
+
+/// The result of applying suggestions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AppliedSuggestions {
+    /// The edited snippet.
+    code: String,
+    /// The indices of the suggestions that were applied.
+    applied: Vec<usize>,
+    /// The indices of selected suggestions that were skipped because they
+    /// overlap a suggestion that was applied.
+    skipped: Vec<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Diagnostic {
+    message: String,
+    #[serde(default)]
+    spans: Vec<DiagnosticSpan>,
+    #[serde(default)]
+    children: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiagnosticSpan {
+    file_name: String,
+    byte_start: usize,
+    byte_end: usize,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<String>,
+}
+
+/// Returns the machine-applicable suggestions for `code` found in
+/// `diagnostics`, which is the json output of cargo or rustc, one message
+/// per line.
+pub fn list_suggestions(code: &str, diagnostics: &str) -> Vec<Suggestion> {
+    let mut suggestions: Vec<Suggestion> = Vec::new();
+    for diagnostic in parse_diagnostics(diagnostics) {
+        collect_suggestions(code, &diagnostic, &mut suggestions);
+    }
+    suggestions
+}
+
+/// Applies the suggestions with the given indices, or all of them if
+/// `selected` is `None`. Suggestions are applied in order; any that overlap
+/// an earlier suggestion are skipped.
+pub fn apply_suggestions(
+    code: &str,
+    diagnostics: &str,
+    selected: Option<&[usize]>,
+) -> AppliedSuggestions {
+    let suggestions = list_suggestions(code, diagnostics);
+    let is_selected = |s: &Suggestion| match selected {
+        Some(selected) => selected.contains(&s.index),
+        None => true,
+    };
+
+    let mut edits: Vec<&Edit> = Vec::new();
+    let mut applied = Vec::new();
+    let mut skipped = Vec::new();
+    for suggestion in suggestions.iter().filter(|s| is_selected(s)) {
+        if suggestion.edits.iter().any(|e| edits.iter().any(|other| e.overlaps(other))) {
+            skipped.push(suggestion.index);
+        } else {
+            edits.extend(suggestion.edits.iter());
+            applied.push(suggestion.index);
+        }
+    }
+
+    // apply from the end, so that earlier offsets remain valid
+    edits.sort_by_key(|e| std::cmp::Reverse((e.start, e.end)));
+    let mut code = code.to_owned();
+    for edit in edits {
+        code.replace_range(edit.start..edit.end, &edit.replacement);
+    }
+    AppliedSuggestions { code, applied, skipped }
+}
+
+impl Edit {
+    /// Two insertions at the same point also overlap, since there is no
+    /// correct order in which to apply them.
+    fn overlaps(&self, other: &Edit) -> bool {
+        (self.start < other.end && other.start < self.end)
+            || (self.start == other.start && (self.is_insertion() || other.is_insertion()))
+    }
+
+    fn is_insertion(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+fn parse_diagnostics(text: &str) -> Vec<Diagnostic> {
+    text.lines()
+        .filter(|l| l.starts_with('{'))
+        .filter_map(|line| {
+            match serde_json::from_str::<CargoMessage>(line) {
+                Ok(msg) if msg.reason == "compiler-message" => return msg.message,
+                Ok(_) => return None,
+                Err(_) => (),
+            }
+            serde_json::from_str::<Diagnostic>(line).ok()
+        })
+        .collect()
+}
+
+/// Each diagnostic, and each of its children, contributes at most one
+/// suggestion, made up of all of its suggested spans.
+fn collect_suggestions(code: &str, diagnostic: &Diagnostic, out: &mut Vec<Suggestion>) {
+    let edits = diagnostic
+        .spans
+        .iter()
+        .filter(|span| span.suggestion_applicability.as_deref() == Some("MachineApplicable"))
+        .map(|span| {
+            let replacement = span.suggested_replacement.clone()?;
+            let file = Path::new(&span.file_name);
+            let start = snippet_offset_for_source(code, file, span.byte_start)?;
+            let end = snippet_offset_for_source(code, file, span.byte_end)?;
+            Some(Edit { start, end, replacement })
+        })
+        .collect::<Option<Vec<_>>>();
+
+    match edits {
+        // duplicates are common, e.g. when a snippet is built as a bin and a test
+        Some(edits) if !edits.is_empty() && !out.iter().any(|s| s.edits == edits) => {
+            out.push(Suggestion { index: out.len(), message: diagnostic.message.clone(), edits })
+        }
+        _ => (),
+    }
+
+    for child in diagnostic.children.iter() {
+        collect_suggestions(code, child, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize, replacement: &str) -> String {
+        format!(
+            r#"{{"file_name":"src/main.rs","byte_start":{},"byte_end":{},"suggested_replacement":"{}","suggestion_applicability":"MachineApplicable"}}"#,
+            start, end, replacement
+        )
+    }
+
+    fn message(children: &[(&str, Vec<String>)]) -> String {
+        let children = children
+            .iter()
+            .map(|(msg, spans)| {
+                format!(r#"{{"message":"{}","spans":[{}],"children":[]}}"#, msg, spans.join(","))
+            })
+            .collect::<Vec<_>>();
+        format!(
+            r#"{{"reason":"compiler-message","message":{{"message":"warning","spans":[],"children":[{}]}}}}"#,
+            children.join(",")
+        )
+    }
+
+    #[test]
+    fn apply_and_skip_overlapping() {
+        let code = "//~ use itoa\nfn main() {\n    let mut x = 5;\n    let mut y = 6;\n}\n";
+        let mut_x = code.find("mut x").unwrap();
+        let mut_y = code.find("mut y").unwrap();
+        let diagnostics = [
+            message(&[("remove this `mut`", vec![span(mut_x, mut_x + 4, "")])]),
+            message(&[("rename", vec![span(mut_x + 4, mut_x + 5, "_x")])]),
+            message(&[("overlaps", vec![span(mut_x, mut_x + 5, "z")])]),
+            message(&[("remove this `mut`", vec![span(mut_y, mut_y + 4, "")])]),
+            r#"{"reason":"build-finished","success":true}"#.to_string(),
+        ]
+        .join("\n");
+
+        assert_eq!(list_suggestions(code, &diagnostics).len(), 4);
+        let result = apply_suggestions(code, &diagnostics, None);
+        assert_eq!(result.code, "//~ use itoa\nfn main() {\n    let _x = 5;\n    let y = 6;\n}\n");
+        assert_eq!(result.applied, vec![0, 1, 3]);
+        assert_eq!(result.skipped, vec![2]);
+
+        let result = apply_suggestions(code, &diagnostics, Some(&[2]));
+        assert_eq!(
+            result.code,
+            "//~ use itoa\nfn main() {\n    let z = 5;\n    let mut y = 6;\n}\n"
+        );
+    }
+
+    #[test]
+    fn ignore_other_files_and_bad_offsets() {
+        let code = "fn main() {}\n";
+        let other = span(0, 2, "x").replace("src/main.rs", "build.rs");
+        let out_of_range = span(0, 100, "x");
+        let dependency = span(0, 2, "x").replace(
+            "src/main.rs",
+            "/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/itoa-1.0.9/src/lib.rs",
+        );
+        let diagnostics =
+            message(&[("a", vec![other]), ("b", vec![out_of_range]), ("c", vec![dependency])]);
+        assert!(list_suggestions(code, &diagnostics).is_empty());
+    }
+}
@@ -0,0 +1,377 @@
+//! Starting points for new snippets: a few built-in templates, and any the
+//! user has saved to disk.
+//!
+//! Templates can contain placeholders, written `${name}` or
+//! `${name:default}`, which are filled in when a snippet is created.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// User templates are stored as `<name>.rs` in the templates directory.
+const EXTENSION: &str = "rs";
+/// A user template's first line may give its description.
+const DESCRIPTION_PREFIX: &str = "//@ ";
+
+/// A template, as listed for the user to choose from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Template {
+    name: String,
+    description: String,
+    /// The template's code, placeholders included.
+    code: String,
+    /// Each distinct placeholder in the code, in order of appearance.
+    placeholders: Vec<Placeholder>,
+    /// `false` for templates read from the user's templates directory.
+    builtin: bool,
+}
+
+/// A value to be filled in when the template is used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Placeholder {
+    name: String,
+    /// Used if no value is given; if `None`, a value is required.
+    default: Option<String>,
+}
+
+impl Template {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn placeholders(&self) -> &[Placeholder] {
+        &self.placeholders
+    }
+
+    fn new(name: &str, description: &str, code: &str, builtin: bool) -> Self {
+        Template {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            code: code.to_owned(),
+            placeholders: find_placeholders(code),
+            builtin,
+        }
+    }
+
+    /// The template's code with each placeholder replaced by its value in
+    /// `values`, or else its default.
+    pub fn instantiate(&self, values: &HashMap<String, String>) -> Result<String, Error> {
+        let mut result = String::with_capacity(self.code.len());
+        let mut rest = self.code.as_str();
+        while let Some((start, end, name, default)) = next_placeholder(rest) {
+            let value = match (values.get(name), default) {
+                (Some(value), _) => value.as_str(),
+                (None, Some(default)) => default,
+                (None, None) => {
+                    return Err(Error::MissingPlaceholder(self.name.clone(), name.to_owned()))
+                }
+            };
+            result.push_str(&rest[..start]);
+            result.push_str(value);
+            rest = &rest[end..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+impl Placeholder {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn default(&self) -> Option<&str> {
+        self.default.as_deref()
+    }
+}
+
+/// `(name, description, code)` of each built-in template.
+const BUILTIN_TEMPLATES: &[(&str, &str, &str)] = &[
+    ("hello-world", "Prints a greeting.", HELLO_WORLD),
+    ("test-module", "A function with a module of unit tests. Run it as a test.", TEST_MODULE),
+    (
+        "bench",
+        "A benchmark using the unstable test crate. Run it as a test on a nightly toolchain.",
+        BENCH,
+    ),
+    ("async-tokio", "An async main function on the tokio runtime.", ASYNC_TOKIO),
+    ("no-std-library", "A library that does not use the standard library.", NO_STD_LIBRARY),
+    ("ffi", "Calls into the C library, and exports a function to C.", FFI),
+];
+
+const HELLO_WORLD: &str = r#"fn main() {
+    println!("Hello, ${name:world}!");
+}
+"#;
+
+const TEST_MODULE: &str = r#"pub fn ${function:add}(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(${function:add}(2, 2), 4);
+    }
+}
+"#;
+
+const BENCH: &str = r#"#![feature(test)]
+
+extern crate test;
+
+pub fn ${function:fibonacci}(n: u64) -> u64 {
+    (0..n).fold((0, 1), |(a, b), _| (b, a + b)).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::{black_box, Bencher};
+
+    #[bench]
+    fn bench_${function:fibonacci}(b: &mut Bencher) {
+        b.iter(|| ${function:fibonacci}(black_box(${input:30})));
+    }
+}
+"#;
+
+const ASYNC_TOKIO: &str = r#"//~ use tokio = 1 features = macros,rt-multi-thread,time
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    let task = tokio::spawn(async {
+        tokio::time::sleep(Duration::from_millis(${delay_ms:100})).await;
+        "${message:done}"
+    });
+    println!("{}", task.await.unwrap());
+}
+"#;
+
+const NO_STD_LIBRARY: &str = r#"#![no_std]
+
+//! ${description:A library without the standard library.}
+
+/// Returns the sum of `values`, or `None` on overflow.
+pub fn checked_sum(values: &[u32]) -> Option<u32> {
+    values.iter().try_fold(0u32, |sum, &v| sum.checked_add(v))
+}
+"#;
+
+const FFI: &str = r#"use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+
+extern "C" {
+    fn abs(x: c_int) -> c_int;
+    fn strlen(s: *const c_char) -> usize;
+}
+
+#[no_mangle]
+pub extern "C" fn ${function:double_it}(x: c_int) -> c_int {
+    x * 2
+}
+
+fn main() {
+    let text = CString::new("${text:hello from C}").unwrap();
+    unsafe {
+        println!("abs(-3) = {}", abs(-3));
+        println!("strlen = {}", strlen(text.as_ptr()));
+    }
+    println!("{}", ${function:double_it}(21));
+}
+"#;
+
+/// Lists the built-in templates, then those in `user_dir` by name. A user
+/// template with the same name as a built-in one replaces it.
+pub fn list_templates(user_dir: Option<&Path>) -> Vec<Template> {
+    let mut user = user_dir.map(read_user_templates).unwrap_or_default();
+    let mut templates = BUILTIN_TEMPLATES
+        .iter()
+        .map(|(name, description, code)| match user.iter().position(|t| t.name == *name) {
+            Some(idx) => user.remove(idx),
+            None => Template::new(name, description, code, true),
+        })
+        .collect::<Vec<_>>();
+    templates.extend(user);
+    templates
+}
+
+/// Creates a snippet from the template called `name`, filling its
+/// placeholders from `values`.
+pub fn instantiate_template(
+    name: &str,
+    user_dir: Option<&Path>,
+    values: &HashMap<String, String>,
+) -> Result<String, Error> {
+    list_templates(user_dir)
+        .into_iter()
+        .find(|t| t.name == name)
+        .ok_or_else(|| Error::UnknownTemplate(name.to_owned()))?
+        .instantiate(values)
+}
+
+/// Saves `code` as a template in `user_dir`, replacing any template with the
+/// same name. Names may contain only letters, numbers, `-` and `_`.
+pub fn save_template(
+    user_dir: &Path,
+    name: &str,
+    description: &str,
+    code: &str,
+) -> Result<(), Error> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Error::InvalidInput(format!("'{}' is not a valid template name", name)));
+    }
+    fs::create_dir_all(user_dir).map_err(|_| Error::CreateOutputFailed(user_dir.to_owned()))?;
+
+    let path = user_dir.join(name).with_extension(EXTENSION);
+    let description = description.lines().next().unwrap_or_default().trim();
+    let contents = if description.is_empty() {
+        code.to_owned()
+    } else {
+        format!("{}{}\n{}", DESCRIPTION_PREFIX, description, code)
+    };
+    fs::write(&path, contents).map_err(|_| Error::CreateOutputFailed(path))
+}
+
+fn read_user_templates(dir: &Path) -> Vec<Template> {
+    // a missing directory just means nothing has been saved yet
+    let mut templates = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == EXTENSION).unwrap_or(false))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?.to_owned();
+            let contents = fs::read_to_string(&path).ok()?;
+            Some(parse_user_template(&name, &contents))
+        })
+        .collect::<Vec<_>>();
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    templates
+}
+
+fn parse_user_template(name: &str, contents: &str) -> Template {
+    let (description, code) = match contents.strip_prefix(DESCRIPTION_PREFIX) {
+        Some(rest) => rest.split_once('\n').unwrap_or((rest, "")),
+        None => ("", contents),
+    };
+    Template::new(name, description.trim(), code, false)
+}
+
+fn find_placeholders(code: &str) -> Vec<Placeholder> {
+    let mut placeholders: Vec<Placeholder> = Vec::new();
+    let mut rest = code;
+    while let Some((_, end, name, default)) = next_placeholder(rest) {
+        if !placeholders.iter().any(|p| p.name == name) {
+            let default = default.map(str::to_owned);
+            placeholders.push(Placeholder { name: name.to_owned(), default });
+        }
+        rest = &rest[end..];
+    }
+    placeholders
+}
+
+/// Finds the next `${name}` or `${name:default}` in `text`, returning its
+/// start and end offsets, name, and default. Anything else starting with
+/// `${` is left alone, as it may be part of the code.
+fn next_placeholder(text: &str) -> Option<(usize, usize, &str, Option<&str>)> {
+    let mut offset = 0;
+    while let Some(idx) = text[offset..].find("${") {
+        let start = offset + idx;
+        let body_start = start + 2;
+        if let Some(len) = text[body_start..].find('}') {
+            let body = &text[body_start..body_start + len];
+            let (name, default) = match body.split_once(':') {
+                Some((name, default)) => (name, Some(default)),
+                None => (body, None),
+            };
+            let valid_name = !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if valid_name && !default.map(|d| d.contains('\n')).unwrap_or(false) {
+                return Some((start, body_start + len + 1, name, default));
+            }
+        }
+        offset = body_start;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        let template = Template::new(
+            "t",
+            "",
+            "fn ${function}() { ${body:todo!()} }\nfn ${function}_again() {}\n${Not} ${x",
+            true,
+        );
+        assert_eq!(
+            template.placeholders(),
+            &[
+                Placeholder { name: "function".into(), default: None },
+                Placeholder { name: "body".into(), default: Some("todo!()".into()) },
+            ]
+        );
+
+        let mut values = HashMap::new();
+        assert!(matches!(
+            template.instantiate(&values),
+            Err(Error::MissingPlaceholder(t, p)) if t == "t" && p == "function"
+        ));
+        values.insert("function".to_owned(), "go".to_owned());
+        assert_eq!(
+            template.instantiate(&values).unwrap(),
+            "fn go() { todo!() }\nfn go_again() {}\n${Not} ${x"
+        );
+    }
+
+    #[test]
+    fn builtins_have_defaults() {
+        let templates = list_templates(None);
+        assert_eq!(templates.len(), BUILTIN_TEMPLATES.len());
+        for template in templates {
+            let code = template.instantiate(&HashMap::new()).unwrap();
+            assert!(!code.contains("${"), "{} left a placeholder", template.name());
+        }
+    }
+
+    #[test]
+    fn user_templates() {
+        let dir = tempdir::TempDir::new("templates").unwrap();
+        save_template(dir.path(), "mine", "My template.\nignored", "// ${greeting:hi}\n").unwrap();
+        save_template(dir.path(), "hello-world", "", "fn main() {}\n").unwrap();
+        assert!(save_template(dir.path(), "../escape", "", "").is_err());
+
+        let templates = list_templates(Some(dir.path()));
+        let hello = templates.iter().find(|t| t.name() == "hello-world").unwrap();
+        assert!(!hello.builtin);
+        assert_eq!(hello.code(), "fn main() {}\n");
+
+        let mine = templates.last().unwrap();
+        assert_eq!(mine.name(), "mine");
+        assert_eq!(mine.description, "My template.");
+        assert_eq!(mine.code(), "// ${greeting:hi}\n");
+
+        let mut values = HashMap::new();
+        values.insert("greeting".to_owned(), "hello".to_owned());
+        assert_eq!(instantiate_template("mine", Some(dir.path()), &values).unwrap(), "// hello\n");
+        assert!(matches!(
+            instantiate_template("missing", Some(dir.path()), &values),
+            Err(Error::UnknownTemplate(_))
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+//! Collecting compile-time profiling data from `cargo build --timings`
+//! and, on nightly, `-Ztime-passes`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The maximum number of compiler passes included in a report.
+const SLOWEST_PASSES_COUNT: usize = 20;
+
+/// A summary of where time was spent during a build.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimingsReport {
+    /// Per-crate build durations, slowest first.
+    units: Vec<UnitTiming>,
+    /// The slowest compiler passes, summed across all crates. This is only
+    /// available on nightly toolchains.
+    passes: Vec<PassTiming>,
+    /// The HTML report generated by cargo, if it exists.
+    html_report: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnitTiming {
+    name: String,
+    version: String,
+    /// The target, e.g. `build script`; empty for the lib or bin target.
+    #[serde(default)]
+    target: String,
+    #[serde(rename(deserialize = "duration"))]
+    duration_secs: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PassTiming {
+    name: String,
+    duration_secs: f64,
+}
+
+impl TimingsReport {
+    /// Builds a report from cargo's timing output in `outdir` and any
+    /// `-Ztime-passes` lines collected from stderr.
+    pub(crate) fn collect(outdir: &Path, pass_lines: &[String]) -> Self {
+        let html_path = outdir.join("target").join("cargo-timings").join("cargo-timing.html");
+        let mut units = fs::read_to_string(&html_path)
+            .ok()
+            .and_then(|html| parse_unit_data(&html))
+            .unwrap_or_default();
+        units.sort_by(|a, b| b.duration_secs.partial_cmp(&a.duration_secs).unwrap());
+
+        let html_report = if html_path.exists() { Some(html_path) } else { None };
+        let passes = summarize_passes(pass_lines.iter().filter_map(|l| parse_pass_line(l)));
+        TimingsReport { units, passes, html_report }
+    }
+}
+
+/// The timings page embeds its data as a javascript constant; we pull that out.
+fn parse_unit_data(html: &str) -> Option<Vec<UnitTiming>> {
+    const UNIT_DATA_DECL: &str = "const UNIT_DATA = ";
+    let start = html.find(UNIT_DATA_DECL)? + UNIT_DATA_DECL.len();
+    let len = html[start..].find(";\n")?;
+    serde_json::from_str(&html[start..start + len]).ok()
+}
+
+/// Returns `true` if this line looks like `-Ztime-passes` output.
+pub(crate) fn is_pass_line(line: &str) -> bool {
+    line.trim_start().starts_with("time:")
+}
+
+/// Parses lines of the form,
+/// `time:   0.002; rss:   25MB ->   27MB (   +2MB)\tparse_crate`.
+fn parse_pass_line(line: &str) -> Option<PassTiming> {
+    let line = line.trim();
+    let rest = line.trim_start_matches("time:").trim_start();
+    let secs_end = rest.find(|c: char| c == ';' || c.is_whitespace())?;
+    let duration_secs = rest[..secs_end].parse().ok()?;
+    let name = match rest.rfind('\t') {
+        Some(idx) => &rest[idx + 1..],
+        None => rest.rsplit(' ').next()?,
+    };
+    Some(PassTiming { name: name.trim().to_owned(), duration_secs })
+}
+
+fn summarize_passes(passes: impl Iterator<Item = PassTiming>) -> Vec<PassTiming> {
+    let mut totals: HashMap<String, f64> = HashMap::new();
+    for pass in passes {
+        *totals.entry(pass.name).or_default() += pass.duration_secs;
+    }
+
+    let mut passes = totals
+        .into_iter()
+        .map(|(name, duration_secs)| PassTiming { name, duration_secs })
+        .collect::<Vec<_>>();
+    passes.sort_by(|a, b| b.duration_secs.partial_cmp(&a.duration_secs).unwrap());
+    passes.truncate(SLOWEST_PASSES_COUNT);
+    passes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_lines() {
+        let pass = parse_pass_line("time:   0.250; rss:   25MB ->   27MB (   +2MB)\tparse_crate");
+        assert_eq!(pass, Some(PassTiming { name: "parse_crate".into(), duration_secs: 0.25 }));
+        let pass = parse_pass_line("  time: 1.500\tcodegen");
+        assert_eq!(pass, Some(PassTiming { name: "codegen".into(), duration_secs: 1.5 }));
+        assert!(parse_pass_line("time: soon\tcodegen").is_none());
+    }
+
+    #[test]
+    fn summarize() {
+        let passes = vec![
+            PassTiming { name: "codegen".into(), duration_secs: 1.0 },
+            PassTiming { name: "parse".into(), duration_secs: 0.5 },
+            PassTiming { name: "codegen".into(), duration_secs: 2.0 },
+        ];
+        let summary = summarize_passes(passes.into_iter());
+        assert_eq!(summary[0], PassTiming { name: "codegen".into(), duration_secs: 3.0 });
+        assert_eq!(summary.len(), 2);
+    }
+
+    #[test]
+    fn unit_data() {
+        let html = "<script>\nconst UNIT_DATA = [{\"i\":0,\"name\":\"serde\",\
+                    \"version\":\"1.0.0\",\"mode\":\"todo\",\"target\":\"\",\
+                    \"start\":0.0,\"duration\":1.25}];\nconst CONCURRENCY_DATA = [];";
+        let units = parse_unit_data(html).unwrap();
+        assert_eq!(
+            units,
+            vec![UnitTiming {
+                name: "serde".into(),
+                version: "1.0.0".into(),
+                target: String::new(),
+                duration_secs: 1.25,
+            }]
+        );
+    }
+}
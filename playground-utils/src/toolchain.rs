@@ -1,3 +1,7 @@
+use std::process::Command;
+
+use semver::Version;
+
 use crate::error::Error;
 use crate::rustup;
 
@@ -14,6 +18,10 @@ pub struct ToolchainInfo {
 }
 
 impl ToolchainInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn from_name(name: String) -> Result<Self, Error> {
         let trimmed = name.trim_end_matches(NATIVE_TOOLCHAIN).trim_end_matches('-');
         let mut split = trimmed.splitn(2, '-');
@@ -25,6 +33,69 @@ impl ToolchainInfo {
     }
 }
 
+/// Returns `true` if this toolchain name refers to a nightly toolchain.
+pub(crate) fn is_nightly(toolchain: &str) -> bool {
+    toolchain.starts_with("nightly")
+}
+
+/// Returns the target triple of the toolchains that run natively here,
+/// e.g. `aarch64-apple-darwin`.
+pub(crate) fn native_host() -> String {
+    format!("{}{}", std::env::consts::ARCH, NATIVE_TOOLCHAIN)
+}
+
+/// Returns the host target triple of this toolchain, e.g. `x86_64-apple-darwin`.
+pub(crate) fn host_triple(toolchain: &str) -> Result<String, Error> {
+    let output = Command::new("rustup")
+        .args(["run", toolchain, "rustc", "-vV"])
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to query toolchain", &output));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|l| l.starts_with("host: "))
+        .map(|l| l.trim_start_matches("host: ").trim().to_owned())
+        .ok_or_else(|| Error::ToolchainParseError(toolchain.to_owned()))
+}
+
+/// Returns `true` if `component`, e.g. `rust-src`, is installed for this
+/// toolchain, for any target.
+pub(crate) fn has_component(toolchain: &str, component: &str) -> Result<bool, Error> {
+    let output = Command::new("rustup")
+        .args(["component", "list", "--installed", "--toolchain", toolchain])
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to query toolchain", &output));
+    }
+
+    // components are listed with their target, except for `rust-src`
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::trim).any(|l| {
+        l == component || l.strip_prefix(component).map(|t| t.starts_with('-')).unwrap_or(false)
+    }))
+}
+
+/// Returns the version of cargo in this toolchain.
+pub(crate) fn cargo_version(toolchain: &str) -> Result<Version, Error> {
+    let output = Command::new("rustup")
+        .args(["run", toolchain, "cargo", "--version"])
+        .output()
+        .map_err(Error::ToolchainSelectFailed)?;
+    if !output.status.success() {
+        return Err(Error::bad_output("Failed to query toolchain", &output));
+    }
+
+    // e.g. `cargo 1.76.0-nightly (71cd3a926 2023-11-20)`
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|v| Version::parse(v).ok())
+        .ok_or_else(|| Error::ToolchainParseError(toolchain.to_owned()))
+}
+
 /// Lists the installed toolchains for this target (macos)
 pub fn list_toolchains() -> Result<Vec<ToolchainInfo>, Error> {
     let toolchains = rustup::list_toolchains()?;
@@ -1,9 +1,24 @@
+use std::any::Any;
 use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
 
 use libc::{c_char, size_t};
 extern crate xi_modal_input;
 use xi_modal_input::{EventCtx, EventPayload, KeyEvent, Line, OneView, Plumber, Size, Vim, XiCore};
 
+/// Passed to the error callback when a call panicked.
+const ERROR_PANIC: i32 = 1;
+/// Passed to the error callback when a call was given a null pointer,
+/// invalid utf8 or a malformed message, or was made before the core was
+/// ready for it.
+const ERROR_INVALID_INPUT: i32 = 2;
+
+type ErrorCallback = extern "C" fn(i32, *const c_char);
+
+/// Receives errors from any call; if none is set, they are printed.
+static ERROR_CALLBACK: Mutex<Option<ErrorCallback>> = Mutex::new(None);
+
 #[repr(C)]
 pub struct XiLine {
     text: *const c_char,
@@ -13,19 +28,28 @@ pub struct XiLine {
     styles_len: size_t,
 }
 
+/// Sets the callback that receives an error code and message whenever a
+/// call fails, instead of that failure crashing the host.
+#[no_mangle]
+pub extern "C" fn xiSetErrorCallback(callback: ErrorCallback) {
+    *ERROR_CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = Some(callback);
+}
+
 #[no_mangle]
 pub extern "C" fn xiCoreCreate(
     rpc_callback: extern "C" fn(*const c_char),
     invalidate_callback: extern "C" fn(size_t, size_t),
     width_measure_fn: extern "C" fn(*const c_char) -> Size,
 ) -> *const XiCore {
-    let r = Box::into_raw(Box::new(XiCore::new(
-        rpc_callback,
-        invalidate_callback,
-        OneView::new(width_measure_fn),
-    )));
-    eprintln!("xiCore alloc {:?}", &r);
-    r
+    guard("xiCoreCreate", std::ptr::null(), || {
+        let r = Box::into_raw(Box::new(XiCore::new(
+            rpc_callback,
+            invalidate_callback,
+            OneView::new(width_measure_fn),
+        )));
+        eprintln!("xiCore alloc {:?}", &r);
+        Ok(r as *const XiCore)
+    })
 }
 
 #[no_mangle]
@@ -36,15 +60,14 @@ pub extern "C" fn xiCoreRegisterEventHandler(
     timer_cb: extern "C" fn(*const EventPayload, u32) -> u32,
     cancel_timer_cb: extern "C" fn(u32),
 ) {
-    let core = unsafe {
-        assert!(!ptr.is_null(), "null pointer in xiCoreRegisterEventHandler");
-        &mut *ptr
-    };
-
-    let machine = Vim::new();
-    let plumber = Plumber::new(event_cb, action_cb, timer_cb, cancel_timer_cb);
-    core.plumber = Some(plumber);
-    core.handler = Some(Box::new(machine));
+    guard("xiCoreRegisterEventHandler", (), || {
+        let core = unsafe { core_arg(ptr)? };
+        let machine = Vim::new();
+        let plumber = Plumber::new(event_cb, action_cb, timer_cb, cancel_timer_cb);
+        core.plumber = Some(plumber);
+        core.handler = Some(Box::new(machine));
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -54,68 +77,70 @@ pub extern "C" fn xiCoreHandleInput(
     characters: *const c_char,
     payload: *const EventPayload,
 ) {
-    let core = unsafe {
-        assert!(!ptr.is_null());
-        &mut *ptr
-    };
-
-    let cstr = unsafe {
-        assert!(!characters.is_null());
-        CStr::from_ptr(characters)
-    };
-
-    let characters = match cstr.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("invalid cstr: {}, {:?}", e, cstr.to_bytes());
-            ""
+    guard("xiCoreHandleInput", (), || {
+        let core = unsafe { core_arg(ptr)? };
+        // as before, invalid utf8 is handled as an empty string rather than
+        // dropping the event; the key may still mean something, e.g. escape
+        let characters = match unsafe { str_arg(characters, "characters") } {
+            Ok(characters) => characters,
+            Err(e) if !characters.is_null() => {
+                eprintln!("xiCoreHandleInput: {}", e);
+                ""
+            }
+            Err(e) => return Err(e),
+        };
+        let event = KeyEvent { modifiers, characters, payload };
+
+        let (plumber, handler) = match (core.plumber.as_ref(), core.handler.as_mut()) {
+            (Some(plumber), Some(handler)) => (plumber, handler),
+            _ => return Err("no event handler has been registered".into()),
+        };
+        let ctx = EventCtx { plumber, state: &mut core.state };
+        if let Some(update) = handler.handle_event(event, ctx) {
+            core.send_update(update);
         }
-    };
-
-    let event = KeyEvent { modifiers, characters, payload };
-
-    let ctx = EventCtx { plumber: core.plumber.as_ref().unwrap(), state: &mut core.state };
-    if let Some(update) = core.handler.as_mut().unwrap().handle_event(event, ctx) {
-        core.send_update(update);
-    }
+        Ok(())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn xiCoreClearPending(ptr: *mut XiCore, token: u32) {
-    let core = unsafe {
-        assert!(!ptr.is_null());
-        &mut *ptr
-    };
-
-    if core.handler.is_none() {
-        eprintln!("unexpected None in xiCoreClearPending");
-    }
-
-    if let Some(h) = core.handler.as_mut() {
-        h.clear_pending(token)
-    };
+    guard("xiCoreClearPending", (), || {
+        let core = unsafe { core_arg(ptr)? };
+        match core.handler.as_mut() {
+            Some(h) => h.clear_pending(token),
+            None => return Err("no event handler has been registered".into()),
+        }
+        Ok(())
+    })
 }
 
+/// Returns null if there is no line at `idx`, or if the call fails.
 #[no_mangle]
 pub extern "C" fn xiCoreGetLine(ptr: *mut XiCore, idx: u32) -> *const XiLine {
-    let core = unsafe {
-        assert!(!ptr.is_null(), "null pointer in xiCoreGetLine");
-        &mut *ptr
-    };
-
-    match core.state.get_line(idx as usize) {
-        Some(Line { line, caret, selection, styles }) => {
-            let text = CString::new(line.as_ref()).expect("bad string, very sad").into_raw();
-            let styles_len = styles.len();
-            let styles = Box::into_raw(styles.into_boxed_slice());
-
-            let cursor = caret.map(|v| v as i32).unwrap_or(-1);
-            let xiline =
-                XiLine { text, cursor, selection: [selection.0, selection.1], styles, styles_len };
-            Box::into_raw(Box::new(xiline))
+    guard("xiCoreGetLine", std::ptr::null(), || {
+        let core = unsafe { core_arg(ptr)? };
+        match core.state.get_line(idx as usize) {
+            Some(Line { line, caret, selection, styles }) => {
+                let text = CString::new(line.as_ref())
+                    .map_err(|_| format!("line {} contains a null byte", idx))?
+                    .into_raw();
+                let styles_len = styles.len();
+                let styles = Box::into_raw(styles.into_boxed_slice());
+
+                let cursor = caret.map(|v| v as i32).unwrap_or(-1);
+                let xiline = XiLine {
+                    text,
+                    cursor,
+                    selection: [selection.0, selection.1],
+                    styles,
+                    styles_len,
+                };
+                Ok(Box::into_raw(Box::new(xiline)) as *const XiLine)
+            }
+            None => Ok(std::ptr::null()),
         }
-        None => std::ptr::null(),
-    }
+    })
 }
 
 #[no_mangle]
@@ -125,9 +150,10 @@ pub extern "C" fn xiCoreFree(ptr: *mut XiCore) {
         return;
     }
 
-    unsafe {
-        Box::from_raw(ptr);
-    }
+    guard("xiCoreFree", (), || {
+        unsafe { drop(Box::from_raw(ptr)) };
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -136,9 +162,10 @@ pub extern "C" fn xiCStringFree(ptr: *mut c_char) {
         return;
     }
 
-    unsafe {
-        CString::from_raw(ptr);
-    }
+    guard("xiCStringFree", (), || {
+        unsafe { drop(CString::from_raw(ptr)) };
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -147,32 +174,74 @@ pub extern "C" fn xiLineFree(ptr: *mut XiLine) {
         return;
     }
 
-    unsafe {
-        let line = Box::from_raw(ptr);
-        CString::from_raw(line.text as *mut _);
-        Box::from_raw(line.styles as *mut [usize]);
-    }
+    guard("xiLineFree", (), || {
+        unsafe {
+            let line = Box::from_raw(ptr);
+            drop(CString::from_raw(line.text as *mut _));
+            drop(Box::from_raw(line.styles as *mut [usize]));
+        }
+        Ok(())
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn xiCoreSendMessage(ptr: *mut XiCore, msg: *const c_char) {
-    let core = unsafe {
-        assert!(!ptr.is_null(), "null pointer in xiCoreSendMessage");
-        &mut *ptr
-    };
-
-    let cstr = unsafe {
-        assert!(!ptr.is_null(), "null msg pointer in xiCoreSendMessage");
-        CStr::from_ptr(msg)
-    };
-
-    let msg = match cstr.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("invalid cstr: {}, {:?}", e, cstr.to_bytes());
-            return;
+    guard("xiCoreSendMessage", (), || {
+        let core = unsafe { core_arg(ptr)? };
+        let msg = unsafe { str_arg(msg, "msg")? };
+        core.handle_message(msg).map_err(|e| e.to_string())
+    })
+}
+
+/// Runs the body of an exported function, so that neither a panic nor an
+/// error escapes to the host. Either is reported to the error callback, and
+/// `default` is returned in place of a result.
+///
+/// The core may be left in an inconsistent state by a panic, but the host
+/// can at least save its work.
+fn guard<T, F>(name: &str, default: T, f: F) -> T
+where
+    F: FnOnce() -> Result<T, String>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(msg)) => {
+            report_error(ERROR_INVALID_INPUT, &format!("{}: {}", name, msg));
+            default
+        }
+        Err(payload) => {
+            report_error(ERROR_PANIC, &format!("panic in {}: {}", name, panic_message(&payload)));
+            default
         }
-    };
+    }
+}
 
-    core.handle_message(msg);
+fn report_error(code: i32, msg: &str) {
+    let callback = *ERROR_CALLBACK.lock().unwrap_or_else(|e| e.into_inner());
+    match (callback, CString::new(msg.replace('\0', ""))) {
+        (Some(callback), Ok(msg)) => callback(code, msg.as_ptr()),
+        _ => eprintln!("xi-ffi error {}: {}", code, msg),
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+unsafe fn core_arg<'a>(ptr: *mut XiCore) -> Result<&'a mut XiCore, String> {
+    ptr.as_mut().ok_or_else(|| "null core pointer".to_owned())
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("null {} pointer", name));
+    }
+    let cstr = CStr::from_ptr(ptr);
+    cstr.to_str().map_err(|e| format!("invalid {}: {}, {:?}", name, e, cstr.to_bytes()))
 }
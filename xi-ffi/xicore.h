@@ -21,6 +21,12 @@ typedef struct _XiSize {
 typedef void (*rpc_callback)(json);
 typedef XiSize (*width_measure_fn)(const char*);
 typedef void (*invalidate_callback)(size_t start, size_t end);
+/// Receives failures from any call: code 1 for a panic, 2 for invalid
+/// input such as a null pointer, a malformed message, or a call before a
+/// handler is registered.
+typedef void (*error_callback)(int32_t code, const char* message);
+
+extern void xiSetErrorCallback(error_callback);
 
 extern XiCore* xiCoreCreate(rpc_callback, invalidate_callback, width_measure_fn);
 extern void xiCoreFree(XiCore*);
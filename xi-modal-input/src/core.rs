@@ -6,7 +6,7 @@ use xi_core_lib::rpc::EditNotification;
 use crate::callbacks::{InvalidateCallback, RpcCallback};
 use crate::input_handler::{Handler, Plumber};
 use crate::lines::Size;
-use crate::rpc::Rpc;
+use crate::rpc::{Rpc, RpcError};
 use crate::update::Update;
 use crate::view::OneView;
 
@@ -29,30 +29,35 @@ impl XiCore {
         XiCore { invalidate_callback, rpc_callback, state, plumber: None, handler: None }
     }
 
-    pub fn handle_message(&mut self, msg: &str) {
+    /// Handles a message from the frontend. Malformed messages, and methods
+    /// the core does not handle, are rejected before anything changes.
+    pub fn handle_message(&mut self, msg: &str) -> Result<(), RpcError> {
         use xi_core_lib::rpc::*;
         use EditNotification as E;
-        let msg: Rpc = match serde_json::from_str(msg) {
-            Ok(rpc) => rpc,
-            Err(e) => {
-                eprintln!("invalid json '{}': {}", msg, e);
-                return;
-            }
-        };
+        let msg: Rpc =
+            serde_json::from_str(msg).map_err(|e| RpcError::InvalidMessage(e.to_string()))?;
 
         eprintln!("core handle_msg {:?}", msg.method);
 
         if msg.method == "modify_user_config" {
-            let changes = msg.params["changes"].as_object().unwrap().to_owned();
+            let changes = msg.params["changes"]
+                .as_object()
+                .ok_or_else(|| RpcError::invalid_params(msg.method, "'changes' is not an object"))?
+                .to_owned();
             if let Some(update) = self.state.update_config(changes) {
                 self.send_update(update);
             }
-            return;
+            return Ok(());
         }
 
         let event = match msg.method {
-            "insert" => E::Insert { chars: msg.params["chars"].as_str().unwrap().to_owned() },
-            "viewport_change" => E::ViewportChange(msg.get_params()),
+            "insert" => {
+                let chars = msg.params["chars"].as_str().ok_or_else(|| {
+                    RpcError::invalid_params(msg.method, "'chars' is not a string")
+                })?;
+                E::Insert { chars: chars.to_owned() }
+            }
+            "viewport_change" => E::ViewportChange(msg.get_params()?),
             "gesture" => {
                 #[derive(Deserialize)]
                 struct Params {
@@ -60,21 +65,16 @@ impl XiCore {
                     col: u64,
                     ty: GestureType,
                 }
-                let Params { line, col, ty } = msg.get_params();
+                let Params { line, col, ty } = msg.get_params()?;
                 E::Gesture { line, col, ty }
             }
-            other => match event_from_str(other) {
-                Some(event) => event,
-                None => {
-                    eprintln!("no event for '{}'", other);
-                    return;
-                }
-            },
+            other => event_from_str(other).ok_or_else(|| RpcError::UnknownMethod(other.into()))?,
         };
 
         let domain: EventDomain = event.into();
         let update = self.state.handle_event(domain);
         self.send_update(update);
+        Ok(())
     }
 
     pub fn send_update(&self, mut update: Update) {
@@ -183,4 +183,17 @@ mod tests {
         assert_eq!(rpc.method, "hello");
         assert_eq!(rpc.params.as_object().unwrap().get("foo").unwrap().as_str(), Some("bar"));
     }
+
+    #[test]
+    fn malformed_params() {
+        let rpc: Rpc = serde_json::from_str(r#"{"method": "gesture", "params": {}}"#).unwrap();
+        #[derive(Debug, Deserialize)]
+        struct Params {
+            _line: u64,
+        }
+        match rpc.get_params::<Params>() {
+            Err(RpcError::InvalidParams { method, .. }) => assert_eq!(method, "gesture"),
+            other => panic!("expected invalid params, got {:?}", other),
+        }
+    }
 }
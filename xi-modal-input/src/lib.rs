@@ -21,5 +21,6 @@ mod vim;
 pub use crate::core::XiCore;
 pub use input_handler::{EventCtx, EventPayload, Handler, KeyEvent, Plumber};
 pub use lines::Size;
+pub use rpc::RpcError;
 pub use view::{Line, OneView};
 pub use vim::Machine as Vim;
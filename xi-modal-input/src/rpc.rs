@@ -1,3 +1,5 @@
+use std::fmt;
+
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 
@@ -7,8 +9,40 @@ pub struct Rpc<'a> {
     pub params: Value,
 }
 
+/// Why a message from the frontend could not be handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// The message was not json with a `method` and `params`.
+    InvalidMessage(String),
+    /// The core does not handle this method.
+    UnknownMethod(String),
+    /// The params were not what the method expects.
+    InvalidParams { method: String, reason: String },
+}
+
 impl<'a> Rpc<'a> {
-    pub fn get_params<T: DeserializeOwned>(self) -> T {
-        serde_json::from_value(self.params).unwrap()
+    pub fn get_params<T: DeserializeOwned>(self) -> Result<T, RpcError> {
+        let method = self.method;
+        serde_json::from_value(self.params).map_err(|e| RpcError::invalid_params(method, e))
+    }
+}
+
+impl RpcError {
+    pub(crate) fn invalid_params<D: fmt::Display>(method: &str, reason: D) -> Self {
+        RpcError::InvalidParams { method: method.to_owned(), reason: reason.to_string() }
     }
 }
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::InvalidMessage(reason) => write!(f, "invalid message: {}", reason),
+            RpcError::UnknownMethod(method) => write!(f, "unknown method '{}'", method),
+            RpcError::InvalidParams { method, reason } => {
+                write!(f, "invalid params for '{}': {}", method, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}